@@ -0,0 +1,152 @@
+// Records a session as an asciicast v2 file, the format asciinema plays.
+//
+// The file is a JSON header line, then one JSON array per event:
+//   [seconds, "o", text]   output, the escape sequences that redraw what changed on the screen
+//   [seconds, "i", text]   input, a key typed on the keyboard
+// Time is emulated time, so a recording of a headless run plays at the speed of the real machine.
+
+use std::io::{self, Write};
+
+use super::screen::{Screen, ScreenChanges};
+
+fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 || c as u32 == 0x7f => {
+                json.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// Escape sequences that draw the changed lines and put the cursor where the screen has it
+fn redraw(screen: &Screen, changes: ScreenChanges) -> String {
+    let mut text = String::new();
+    for line in changes.dirty_lines() {
+        text.push_str(&format!("\x1b[{};1H{}", line + 1, screen.get_line(line)));
+    }
+    if !changes.is_empty() {
+        let (line, character) = screen.cursor_position();
+        text.push_str(&format!("\x1b[{};{}H", line + 1, character + 1));
+        text.push_str(if screen.cursor_visible() {
+            "\x1b[?25h"
+        } else {
+            "\x1b[?25l"
+        });
+    }
+    text
+}
+
+#[derive(Debug)]
+pub struct Recorder<W: Write> {
+    out: W,
+    // Emulated time the recording started at
+    start_ns: u128,
+}
+
+impl<W: Write> Recorder<W> {
+    // Writes the header, and the screen as it is now as the first frame
+    pub fn new(mut out: W, screen: &Screen, time_ns: u128, title: &str) -> io::Result<Recorder<W>> {
+        writeln!(
+            out,
+            r#"{{"version": 2, "width": 80, "height": 12, "title": {}}}"#,
+            json_string(title)
+        )?;
+        let mut recorder = Recorder {
+            out,
+            start_ns: time_ns,
+        };
+        let frame = format!("\x1b[2J{}", redraw(screen, ScreenChanges::all()));
+        recorder.event(time_ns, "o", &frame)?;
+        Ok(recorder)
+    }
+
+    fn event(&mut self, time_ns: u128, kind: &str, text: &str) -> io::Result<()> {
+        let seconds = time_ns.saturating_sub(self.start_ns) as f64 / 1e9;
+        writeln!(
+            self.out,
+            "[{:.6}, \"{}\", {}]",
+            seconds,
+            kind,
+            json_string(text)
+        )
+    }
+
+    // Call with the changes taken from the screen, nothing is written when there are none
+    pub fn screen(
+        &mut self,
+        time_ns: u128,
+        screen: &Screen,
+        changes: ScreenChanges,
+    ) -> io::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        self.event(time_ns, "o", &redraw(screen, changes))
+    }
+
+    // A key code sent to the keyboard
+    pub fn key(&mut self, time_ns: u128, code: u8) -> io::Result<()> {
+        self.event(time_ns, "i", &(code as char).to_string())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DP2200::peripheral::Peripheral;
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("HELLO"), r#""HELLO""#);
+        assert_eq!(json_string("\x1b[1;1H\"\\\r"), r#""\u001b[1;1H\"\\\r""#);
+    }
+
+    #[test]
+    fn test_recording() {
+        let mut screen = Screen::new();
+        screen.take_changes();
+        let mut recorder = Recorder::new(Vec::new(), &screen, 1_000_000_000, "Games").unwrap();
+
+        screen.set_vertical(2);
+        screen.write_data(b'A');
+        let changes = screen.take_changes();
+        recorder.screen(1_500_000_000, &screen, changes).unwrap();
+        // Nothing changed, nothing is written
+        recorder
+            .screen(1_600_000_000, &screen, ScreenChanges::default())
+            .unwrap();
+        recorder.key(2_000_000_000, 13).unwrap();
+
+        let cast = String::from_utf8(recorder.finish().unwrap()).unwrap();
+        let lines: Vec<_> = cast.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            r#"{"version": 2, "width": 80, "height": 12, "title": "Games"}"#
+        );
+        assert!(lines[1].starts_with(r#"[0.000000, "o", "\u001b[2J\u001b[1;1H "#));
+        assert_eq!(
+            lines[2],
+            format!(
+                r#"[0.500000, "o", "\u001b[3;1HA{}\u001b[3;1H\u001b[?25l"]"#,
+                " ".repeat(79)
+            )
+        );
+        assert_eq!(lines[3], r#"[1.000000, "i", "\r"]"#);
+    }
+}
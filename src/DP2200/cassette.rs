@@ -9,14 +9,45 @@
 // Rewind:
 // 36 cycles pr byte
 
-use std::collections::VecDeque;
+// Start and stop:
+// The tape needs about 20ms to get up to speed, and as long to come to a stop.
+// 20ms -> 3072 cycles
 
-use log::{error, info};
+use std::{
+    any::Any,
+    fmt,
+    fs::{read, write},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use log::info;
 
 use log::{trace, warn};
 
+use super::{
+    faults::TapeFaults,
+    peripheral::Peripheral,
+    tape_image::{self, ImageError, TapeImage, MAX_TAPE_LENGTH},
+};
+
 pub const CASSETTE_ADDR: u8 = 0o360;
 
+// Number of byte times of gap between two blocks, 28ms at normal speed
+pub const GAP_LENGTH: usize = 10;
+
+// Timing in databus clocks, see the top of the file
+const BYTE_CLOCKS: usize = 431;
+const REWIND_CLOCKS: usize = 36;
+const START_CLOCKS: usize = 3072;
+const STOP_CLOCKS: usize = 3072;
+
+// How much faster the tape runs with TapeTiming::Fast
+const FAST_DIVISOR: usize = 8;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CassetteData {
     Data(u8),
@@ -34,118 +65,509 @@ pub enum MovementDirection {
     Forward,
     Backwards,
 }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    None,
+    // Writing a block, the count is the number of bytes written so far
+    Block(usize),
+    // The cpu did not supply a byte in time, so the block is closed with a gap.
+    // The count is the number of gap bytes left to write.
+    Gap(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapeTiming {
+    // The speeds from the datasheet
+    Datasheet,
+    // Every delay divided by FAST_DIVISOR. Gaps are still a number of byte times,
+    // so they shrink with the bytes, and programs see the same tape only faster.
+    Fast,
+}
+
+impl TapeTiming {
+    fn scale(&self, clocks: usize) -> usize {
+        match self {
+            TapeTiming::Datasheet => clocks,
+            TapeTiming::Fast => (clocks / FAST_DIVISOR).max(1),
+        }
+    }
+
+    // Databus clocks for the head to move one byte
+    pub fn byte_clocks(&self, speed: MovementSpeed) -> Option<usize> {
+        match speed {
+            MovementSpeed::None => None,
+            MovementSpeed::Regular => Some(self.scale(BYTE_CLOCKS)),
+            MovementSpeed::Rewind => Some(self.scale(REWIND_CLOCKS)),
+        }
+    }
+
+    pub fn start_clocks(&self) -> usize {
+        self.scale(START_CLOCKS)
+    }
+
+    pub fn stop_clocks(&self) -> usize {
+        self.scale(STOP_CLOCKS)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeckId {
     Deck1,
     Deck2,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CassetteDeck {
     data: Vec<CassetteData>,
     pub speed: MovementSpeed,
     pub direction: MovementDirection,
     pub head_pos: usize,
     cycle_count: usize,
+    // Databus clocks left before the tape is up to speed, or has come to a stop
+    ramp: usize,
+    pub timing: TapeTiming,
     data_buf: Option<u8>,
     gap_detected: bool,
     ignore_gap: bool,
     stop_on_gap: bool,
+    pub write_mode: WriteMode,
+    write_buf: Option<u8>,
+    // Set when the tape has been written to since it was loaded or saved
+    pub modified: bool,
+    // If set, the tape is saved to this file when it is ejected
+    pub write_back: Option<PathBuf>,
+    pub label: String,
+    pub notes: String,
+    // The write protect tab is removed, nothing can be written to the tape
+    pub write_protected: bool,
+    // Damage to the tape, applied to what the head reads
+    pub faults: Option<TapeFaults>,
+    // The gap the faults read last, forgotten when the tape is written to
+    fault_gap: Option<Range<usize>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TapError {
+    // The file ends inside the length field of a record
+    TruncatedLength {
+        offset: usize,
+        record: usize,
+    },
+    // The record is longer than what is left of the file
+    TruncatedRecord {
+        offset: usize,
+        record: usize,
+        len: usize,
+        available: usize,
+    },
+    // The length after a record does not match the length before it
+    LengthMismatch {
+        offset: usize,
+        record: usize,
+        leading: usize,
+        trailing: usize,
+    },
 }
 
-fn parse_tap(data: &mut Vec<u8>) -> Vec<CassetteData> {
-    let mut cassette_data = Vec::new();
-    let mut gap = [CassetteData::Gap].repeat(10);
-    cassette_data.append(&mut gap);
-    while !data.is_empty() {
-        let sec_len_bytes: [u8; 4] = data[0..4].try_into().unwrap();
-        let sec_len = u32::from_le_bytes(sec_len_bytes) as usize;
-        data.drain(0..4);
+impl fmt::Display for TapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TapError::TruncatedLength { offset, record } => write!(
+                f,
+                "Record {} at byte {}: file ends inside the length field",
+                record, offset
+            ),
+            TapError::TruncatedRecord {
+                offset,
+                record,
+                len,
+                available,
+            } => write!(
+                f,
+                "Record {} at byte {}: length is {} but only {} bytes are left",
+                record, offset, len, available
+            ),
+            TapError::LengthMismatch {
+                offset,
+                record,
+                leading,
+                trailing,
+            } => write!(
+                f,
+                "Record {} at byte {}: trailing length {} does not match leading length {}",
+                record, offset, trailing, leading
+            ),
+        }
+    }
+}
 
-        let d = data.drain(0..sec_len).map(CassetteData::Data);
-        cassette_data.append(&mut d.collect());
-        let mut gap = [CassetteData::Gap].repeat(10);
-        cassette_data.append(&mut gap);
+impl std::error::Error for TapError {}
 
-        data.drain(0..4);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    Tap(TapError),
+    Image(ImageError),
+}
+
+impl From<TapError> for LoadError {
+    fn from(err: TapError) -> LoadError {
+        LoadError::Tap(err)
+    }
+}
+
+impl From<ImageError> for LoadError {
+    fn from(err: ImageError) -> LoadError {
+        LoadError::Image(err)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Tap(err) => err.fmt(f),
+            LoadError::Image(err) => err.fmt(f),
+        }
     }
+}
+
+impl std::error::Error for LoadError {}
+
+fn read_len(tap_file: &[u8], offset: usize, record: usize) -> Result<usize, TapError> {
+    let len_bytes: [u8; 4] = tap_file
+        .get(offset..offset + 4)
+        .and_then(|b| b.try_into().ok())
+        .ok_or(TapError::TruncatedLength { offset, record })?;
+    Ok(u32::from_le_bytes(len_bytes) as usize)
+}
+
+// A tap file is a list of records, each stored as a 4 byte little endian length,
+// the data, and the length again. Without strict checking, a trailing length that
+// is missing at the end of the file, or that does not match, is only logged.
+pub fn read_tap_blocks(tap_file: &[u8], strict: bool) -> Result<Vec<Vec<u8>>, TapError> {
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    let mut record = 0;
+    while offset < tap_file.len() {
+        let sec_len = read_len(tap_file, offset, record)?;
+        let start = offset + 4;
+        let end = start + sec_len;
+        if end > tap_file.len() {
+            return Err(TapError::TruncatedRecord {
+                offset,
+                record,
+                len: sec_len,
+                available: tap_file.len() - start,
+            });
+        }
+
+        blocks.push(tap_file[start..end].to_vec());
+
+        let err = match read_len(tap_file, end, record) {
+            Ok(trailing) if trailing != sec_len => Some(TapError::LengthMismatch {
+                offset: end,
+                record,
+                leading: sec_len,
+                trailing,
+            }),
+            Ok(_) => None,
+            Err(err) => Some(err),
+        };
+        if let Some(err) = err {
+            if strict {
+                return Err(err);
+            }
+            warn!("{}", err);
+        }
+
+        offset = end + 4;
+        record += 1;
+    }
+
+    Ok(blocks)
+}
+
+pub fn write_tap_blocks(blocks: &[Vec<u8>]) -> Vec<u8> {
+    let mut tap_file = Vec::new();
+    for block in blocks {
+        let sec_len = (block.len() as u32).to_le_bytes();
+        tap_file.extend_from_slice(&sec_len);
+        tap_file.extend_from_slice(block);
+        tap_file.extend_from_slice(&sec_len);
+    }
+
+    tap_file
+}
+
+pub fn parse_tap(tap_file: &[u8], strict: bool) -> Result<Vec<CassetteData>, TapError> {
+    let mut cassette_data = [CassetteData::Gap].repeat(GAP_LENGTH);
+    for block in read_tap_blocks(tap_file, strict)? {
+        cassette_data.extend(block.into_iter().map(CassetteData::Data));
+        cassette_data.extend([CassetteData::Gap].repeat(GAP_LENGTH));
+    }
+
+    Ok(cassette_data)
+}
+
+// The inverse of parse_tap. Every run of data becomes a block, gaps only separate blocks.
+pub fn serialize_tap(data: &[CassetteData]) -> Vec<u8> {
+    let blocks: Vec<Vec<u8>> = data
+        .split(|d| *d == CassetteData::Gap)
+        .filter(|b| !b.is_empty())
+        .map(|block| {
+            block
+                .iter()
+                .map(|d| match d {
+                    CassetteData::Data(data) => *data,
+                    CassetteData::Gap => unreachable!(),
+                })
+                .collect()
+        })
+        .collect();
+
+    write_tap_blocks(&blocks)
+}
 
-    cassette_data
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case(extension))
 }
 
 impl CassetteDeck {
-    pub fn new(tap_file: Vec<u8>) -> CassetteDeck {
-        let mut tap_file = tap_file;
-        let data = parse_tap(&mut tap_file);
+    pub fn new(tap_file: Vec<u8>) -> Result<CassetteDeck, TapError> {
+        Ok(CassetteDeck::from_data(parse_tap(&tap_file, false)?))
+    }
+
+    pub fn blank() -> CassetteDeck {
+        CassetteDeck::from_data([CassetteData::Gap].repeat(GAP_LENGTH))
+    }
+
+    // A deck with no tape in it
+    pub fn empty() -> CassetteDeck {
+        CassetteDeck::from_data(Vec::new())
+    }
 
+    pub fn has_tape(&self) -> bool {
+        !self.data.is_empty()
+    }
+
+    pub fn from_data(data: Vec<CassetteData>) -> CassetteDeck {
         CassetteDeck {
             data,
             speed: MovementSpeed::None,
             direction: MovementDirection::Forward,
             head_pos: 0,
             cycle_count: 0,
+            ramp: 0,
+            timing: TapeTiming::Datasheet,
             data_buf: None,
             gap_detected: false,
             ignore_gap: false,
             stop_on_gap: false,
+            write_mode: WriteMode::None,
+            write_buf: None,
+            modified: false,
+            write_back: None,
+            label: String::new(),
+            notes: String::new(),
+            write_protected: false,
+            faults: None,
+            fault_gap: None,
+        }
+    }
+
+    // A tape as it was shelved, with the head where it was left
+    pub fn from_image(image: TapeImage) -> CassetteDeck {
+        let mut deck = CassetteDeck::from_data(image.data);
+        deck.head_pos = image.head_pos.min(deck.data.len().saturating_sub(1));
+        deck.label = image.label;
+        deck.notes = image.notes;
+        deck.write_protected = image.write_protected;
+        deck
+    }
+
+    pub fn to_image(&self) -> TapeImage {
+        TapeImage {
+            label: self.label.clone(),
+            write_protected: self.write_protected,
+            head_pos: self.head_pos,
+            notes: self.notes.clone(),
+            data: self.data.clone(),
         }
     }
 
     fn read_data(&mut self) {
-        match self.data.get(self.head_pos) {
+        let read = match &self.faults {
+            Some(faults) => faults.read(&self.data, self.head_pos, &mut self.fault_gap),
+            None => self.data.get(self.head_pos).copied(),
+        };
+        match read {
             Some(CassetteData::Data(data)) => {
-                self.data_buf = Some(*data);
+                self.data_buf = Some(data);
                 self.gap_detected = false;
                 self.ignore_gap = false;
             }
-            Some(CassetteData::Gap) => {
-                if !self.ignore_gap {
-                    self.data_buf = None;
-                    self.gap_detected = true;
+            Some(CassetteData::Gap) if !self.ignore_gap => {
+                self.data_buf = None;
+                self.gap_detected = true;
 
-                    if self.stop_on_gap {
-                        self.speed = MovementSpeed::None;
-                    }
+                if self.stop_on_gap {
+                    self.brake();
                 }
             }
-            None => {}
+            // A gap that is ignored, or nothing the head could pick up
+            _ => {}
+        }
+    }
+
+    pub fn to_tap(&self) -> Vec<u8> {
+        serialize_tap(&self.data)
+    }
+
+    fn put(&mut self, data: CassetteData) {
+        self.modified = true;
+        self.fault_gap = None;
+        if let Some(cell) = self.data.get_mut(self.head_pos) {
+            *cell = data;
+        } else {
+            self.data.push(data);
         }
     }
 
+    fn write_data(&mut self) {
+        match self.write_mode {
+            WriteMode::None => {}
+            WriteMode::Block(len) => {
+                if let Some(data) = self.write_buf.take() {
+                    self.put(CassetteData::Data(data));
+                    self.write_mode = WriteMode::Block(len + 1);
+                } else if len == 0 {
+                    // Still waiting for the first byte, erase the tape under the head
+                    self.put(CassetteData::Gap);
+                } else {
+                    trace!("Block of {} bytes written, writing gap", len);
+                    self.gap_detected = true;
+                    self.write_mode = WriteMode::Gap(GAP_LENGTH);
+                    self.write_data();
+                }
+            }
+            WriteMode::Gap(remaining) => {
+                self.put(CassetteData::Gap);
+                if remaining > 1 {
+                    self.write_mode = WriteMode::Gap(remaining - 1);
+                } else {
+                    // The gap is complete, leave the head at the end of it
+                    self.write_mode = WriteMode::None;
+                    self.brake();
+                }
+            }
+        }
+    }
+
+    // True at the beginning and at the end of the tape
+    pub fn end_of_tape(&self) -> bool {
+        self.head_pos == 0 || self.head_pos + 1 >= self.data.len()
+    }
+
+    pub fn write_ready(&self) -> bool {
+        matches!(self.write_mode, WriteMode::Block(_)) && self.write_buf.is_none()
+    }
+
+    // Stopped, and done stopping
+    pub fn ready(&self) -> bool {
+        self.speed == MovementSpeed::None && self.ramp == 0 && self.has_tape()
+    }
+
+    // Start moving. The tape has to get up to speed before the head moves,
+    // and has to stop first if it is moving the other way, or is still stopping.
+    fn start(&mut self, direction: MovementDirection, speed: MovementSpeed) {
+        if !self.has_tape() {
+            return;
+        }
+        if self.speed == MovementSpeed::None {
+            self.ramp += self.timing.start_clocks();
+            self.cycle_count = 0;
+        } else if self.direction != direction {
+            self.ramp += self.timing.stop_clocks() + self.timing.start_clocks();
+            self.cycle_count = 0;
+        }
+        self.direction = direction;
+        self.speed = speed;
+    }
+
+    // Stop moving. The head stays where it is, but the deck is not ready until the tape has stopped.
+    fn brake(&mut self) {
+        if self.speed != MovementSpeed::None {
+            self.speed = MovementSpeed::None;
+            self.ramp = self.timing.stop_clocks();
+        }
+    }
+
+    // Stop on the spot, for a tape that is taken out of or put into a deck
+    fn halt(&mut self) {
+        self.stop();
+        self.speed = MovementSpeed::None;
+        self.ramp = 0;
+        self.cycle_count = 0;
+        self.gap_detected = false;
+        self.ignore_gap = false;
+        self.stop_on_gap = false;
+    }
+
+    fn stop(&mut self) {
+        self.brake();
+        self.data_buf = None;
+        self.write_mode = WriteMode::None;
+        self.write_buf = None;
+    }
+
     pub fn update_head(&mut self) {
+        if self.write_mode != WriteMode::None {
+            if self.head_pos + 1 >= MAX_TAPE_LENGTH {
+                // The tape has run out, nothing more fits on it
+                warn!("Cassette write stopped at the end of the tape");
+                self.stop();
+                return;
+            }
+            // Writing always moves forward, and is allowed to extend the tape
+            self.head_pos += 1;
+            self.write_data();
+            return;
+        }
+
         match self.direction {
             MovementDirection::Forward => {
                 if self.head_pos < self.data.len() - 1 {
                     self.head_pos += 1;
                     self.read_data();
                 } else {
-                    self.speed = MovementSpeed::None;
+                    self.brake();
                 }
             }
             MovementDirection::Backwards => {
                 if self.head_pos > 0 {
                     self.head_pos -= 1;
-                    self.read_data();
+                    // The head does not read while rewinding
+                    if self.speed != MovementSpeed::Rewind {
+                        self.read_data();
+                    }
                 } else {
-                    self.speed = MovementSpeed::None;
+                    self.brake();
                 }
             }
         }
     }
 
     pub fn clock(&mut self) {
-        // 153.6kHz clock -> 6.5us cycle
-        // 2.8ms pr byte -> 431 cycles pr byte
-        self.cycle_count += 1;
-
-        let cycle_goal = match self.speed {
-            MovementSpeed::None => None,
-            //MovementSpeed::Regular => Some(431),
-            MovementSpeed::Regular => Some(50),
-            MovementSpeed::Rewind => Some(36),
-        };
+        if self.ramp > 0 {
+            self.ramp -= 1;
+            return;
+        }
 
-        if let Some(cycle_goal) = cycle_goal {
+        if let Some(mut cycle_goal) = self.timing.byte_clocks(self.speed) {
+            if let Some(faults) = &self.faults {
+                cycle_goal = faults.byte_clocks(cycle_goal);
+            }
+            self.cycle_count += 1;
             if self.cycle_count >= cycle_goal {
                 self.cycle_count = 0;
                 self.update_head();
@@ -154,79 +576,55 @@ impl CassetteDeck {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+// A byte read from tape was replaced by the next one before the cpu picked it up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadOverrun {
+    pub deck: DeckId,
+    // Position of the lost byte on the tape
+    pub index: usize,
+    pub data: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Cassette {
     deck1: CassetteDeck,
     deck2: CassetteDeck,
     pub selected_deck: DeckId,
-    data_buffer: VecDeque<u8>,
+    // The byte waiting for the cpu, and where it was on the tape. The next byte from
+    // the tape overwrites it, and read ready stays set, so a slow program only sees that a byte is missing.
+    data_register: Option<(u8, usize)>,
+    overruns: Vec<ReadOverrun>,
+    // Reject tap files with missing or mismatched trailing lengths
+    pub strict_tap: bool,
 }
 
 impl Cassette {
+    // Both decks start out with a blank tape
     pub fn new() -> Cassette {
         Cassette {
-            deck1: CassetteDeck::new(Vec::new()),
-            deck2: CassetteDeck::new(Vec::new()),
+            deck1: CassetteDeck::blank(),
+            deck2: CassetteDeck::blank(),
             selected_deck: DeckId::Deck1,
-            data_buffer: VecDeque::new(),
+            data_register: None,
+            overruns: Vec::new(),
+            strict_tap: false,
         }
     }
 
-    pub fn clock(&mut self) {
-        let mut gap_detected = false;
-        let deck = self.get_selected_deck();
-        deck.clock();
-
-        if let Some(read_data) = deck.data_buf.take() {
-            self.data_buffer.push_front(read_data);
-            self.data_buffer.truncate(2);
-        }
+    pub fn get_selected_deck(&mut self) -> &mut CassetteDeck {
+        self.get_deck(self.selected_deck)
     }
 
-    pub fn get_selected_deck(&mut self) -> &mut CassetteDeck {
-        match self.selected_deck {
+    pub fn get_deck(&mut self, deck: DeckId) -> &mut CassetteDeck {
+        match deck {
             DeckId::Deck1 => &mut self.deck1,
             DeckId::Deck2 => &mut self.deck2,
         }
     }
 
-    pub fn get_status(&mut self) -> u8 {
-        let mut status = 0;
-        if !self.data_buffer.is_empty() {
-            status |= 1 << 2;
-        }
-
-        let deck = self.get_selected_deck();
-        if deck.speed == MovementSpeed::None && !deck.data.is_empty() {
-            status |= 1 << 0;
-        }
-
-        if deck.head_pos == 0 || deck.head_pos == deck.data.len() - 1 {
-            status |= 1 << 1;
-        }
-
-        // Write ready not implemented
-
-        if deck.gap_detected {
-            status |= 1 << 4;
-        }
-
-        if !deck.data.is_empty() {
-            status |= 1 << 6;
-        }
-
-        status
-    }
-
-    pub fn strobe(&mut self) {
-        self.data_buffer.pop_back();
-    }
-
     pub fn ex_tstop(&mut self) {
-        let deck = self.get_selected_deck();
-        deck.speed = MovementSpeed::None;
-        deck.data_buf = None;
-        self.data_buffer.clear();
+        self.get_selected_deck().stop();
+        self.data_register = None;
     }
 
     pub fn ex_deck1(&mut self) {
@@ -239,8 +637,7 @@ impl Cassette {
 
     pub fn ex_rbk(&mut self) {
         let deck = self.get_selected_deck();
-        deck.direction = MovementDirection::Forward;
-        deck.speed = MovementSpeed::Regular;
+        deck.start(MovementDirection::Forward, MovementSpeed::Regular);
         if let Some(CassetteData::Gap) = deck.data.get(deck.head_pos) {
             deck.ignore_gap = true;
         }
@@ -248,10 +645,27 @@ impl Cassette {
         deck.stop_on_gap = true;
     }
 
+    pub fn ex_wbk(&mut self) {
+        let deck = self.get_selected_deck();
+        if !deck.has_tape() {
+            warn!("Cassette write with no tape in the deck ignored");
+            return;
+        }
+        if deck.write_protected {
+            // Without the tab the write electronics are disabled, the deck never becomes write ready
+            warn!("Cassette write to a write protected tape ignored");
+            return;
+        }
+        deck.start(MovementDirection::Forward, MovementSpeed::Regular);
+        deck.gap_detected = false;
+        deck.write_mode = WriteMode::Block(0);
+        deck.write_buf = None;
+        self.data_register = None;
+    }
+
     pub fn ex_bsp(&mut self) {
         let deck = self.get_selected_deck();
-        deck.direction = MovementDirection::Backwards;
-        deck.speed = MovementSpeed::Regular;
+        deck.start(MovementDirection::Backwards, MovementSpeed::Regular);
         if let Some(CassetteData::Gap) = deck.data.get(deck.head_pos) {
             deck.ignore_gap = true;
             deck.gap_detected = false;
@@ -260,8 +674,7 @@ impl Cassette {
 
     pub fn ex_sf(&mut self) {
         let deck = self.get_selected_deck();
-        deck.direction = MovementDirection::Forward;
-        deck.speed = MovementSpeed::Regular;
+        deck.start(MovementDirection::Forward, MovementSpeed::Regular);
         if let Some(CassetteData::Gap) = deck.data.get(deck.head_pos) {
             deck.ignore_gap = true;
         }
@@ -271,8 +684,7 @@ impl Cassette {
 
     pub fn ex_sb(&mut self) {
         let deck = self.get_selected_deck();
-        deck.direction = MovementDirection::Backwards;
-        deck.speed = MovementSpeed::Regular;
+        deck.start(MovementDirection::Backwards, MovementSpeed::Regular);
         if let Some(CassetteData::Gap) = deck.data.get(deck.head_pos) {
             deck.ignore_gap = true;
         }
@@ -280,21 +692,124 @@ impl Cassette {
         deck.stop_on_gap = false;
     }
 
-    pub fn get_data(&mut self) -> u8 {
-        let data = self.data_buffer.back().copied();
-        if let Some(data) = data {
-            return data;
+    pub fn ex_rewind(&mut self) {
+        let deck = self.get_selected_deck();
+        deck.stop();
+        deck.start(MovementDirection::Backwards, MovementSpeed::Rewind);
+        deck.gap_detected = false;
+        deck.ignore_gap = false;
+        deck.stop_on_gap = false;
+        self.data_register = None;
+    }
+
+    // Load a tap file or a tape image, images are recognized by their magic
+    pub fn load(&mut self, deck: DeckId, file: Vec<u8>) -> Result<(), LoadError> {
+        let tape = if tape_image::is_image(&file) {
+            CassetteDeck::from_image(TapeImage::parse(&file)?)
+        } else {
+            CassetteDeck::from_data(parse_tap(&file, self.strict_tap)?)
+        };
+        self.mount(deck, tape);
+        Ok(())
+    }
+
+    // Put a tape in a deck in place of the one there, it runs with the timing of the deck.
+    // The tape that is taken out is returned, stopped where it was.
+    fn mount(&mut self, id: DeckId, mut tape: CassetteDeck) -> CassetteDeck {
+        if id == self.selected_deck {
+            self.data_register = None;
         }
-        0
+        let deck = self.get_deck(id);
+        tape.timing = deck.timing;
+        tape.halt();
+        let mut removed = std::mem::replace(deck, tape);
+        removed.halt();
+        removed
     }
 
-    pub fn write_data(&mut self, data: u8) {}
+    // Put a tape in a deck while the machine runs. The tape that was in the deck
+    // is ejected first, and returned.
+    pub fn insert(&mut self, deck: DeckId, tape: CassetteDeck) -> io::Result<CassetteDeck> {
+        let removed = self.eject(deck)?;
+        self.mount(deck, tape);
+        Ok(removed)
+    }
 
-    pub fn load(&mut self, deck: DeckId, tap_file: Vec<u8>) {
-        match deck {
-            DeckId::Deck1 => self.deck1 = CassetteDeck::new(tap_file),
-            DeckId::Deck2 => self.deck2 = CassetteDeck::new(tap_file),
+    // Move the tape in each deck to the other one
+    pub fn swap(&mut self) -> io::Result<()> {
+        let tape1 = self.eject(DeckId::Deck1)?;
+        let tape2 = self.eject(DeckId::Deck2)?;
+        self.mount(DeckId::Deck1, tape2);
+        self.mount(DeckId::Deck2, tape1);
+        Ok(())
+    }
+
+    pub fn set_write_protected(&mut self, deck: DeckId, write_protected: bool) {
+        self.get_deck(deck).write_protected = write_protected;
+    }
+
+    pub fn set_timing(&mut self, timing: TapeTiming) {
+        self.deck1.timing = timing;
+        self.deck2.timing = timing;
+    }
+
+    // Load a tap file or a tape image from disk. With write_back set, the tape is saved back to
+    // the same file when it is ejected, or when write_back is called on exit.
+    pub fn load_file(&mut self, deck: DeckId, path: &Path, write_back: bool) -> anyhow::Result<()> {
+        let file = read(path).context(format!("Failed to read {}", path.display()))?;
+        // The tape that was in the deck is taken out first
+        self.write_back_deck(deck)
+            .context("Failed to write back the tape in the deck")?;
+        self.load(deck, file)
+            .context(format!("Failed to parse {}", path.display()))?;
+        if write_back {
+            self.get_deck(deck).write_back = Some(path.to_path_buf());
         }
+        Ok(())
+    }
+
+    // Save a tape as a tap file, or as a tape image if the path ends with .dpt
+    pub fn save(&mut self, deck: DeckId, path: &Path) -> io::Result<()> {
+        let deck = self.get_deck(deck);
+        let file = if has_extension(path, tape_image::EXTENSION) {
+            deck.to_image().to_bytes()
+        } else {
+            deck.to_tap()
+        };
+        write(path, file)?;
+        if deck.write_back.as_deref() == Some(path) {
+            deck.modified = false;
+        }
+        Ok(())
+    }
+
+    // Take the tape out of a deck, saving it first if it has a write back file.
+    // The deck is left empty.
+    pub fn eject(&mut self, deck: DeckId) -> io::Result<CassetteDeck> {
+        self.write_back_deck(deck)?;
+        Ok(self.mount(deck, CassetteDeck::empty()))
+    }
+
+    // Save every modified tape that has a write back file
+    pub fn write_back(&mut self) -> io::Result<()> {
+        self.write_back_deck(DeckId::Deck1)?;
+        self.write_back_deck(DeckId::Deck2)
+    }
+
+    fn write_back_deck(&mut self, deck: DeckId) -> io::Result<()> {
+        let tape = self.get_deck(deck);
+        if let Some(path) = tape.write_back.clone() {
+            if tape.modified {
+                info!("Writing tape back to {}", path.display());
+                self.save(deck, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Overruns since the last call
+    pub fn take_overruns(&mut self) -> Vec<ReadOverrun> {
+        std::mem::take(&mut self.overruns)
     }
 
     pub fn get_first_sector(&mut self) -> Vec<u8> {
@@ -303,9 +818,10 @@ impl Cassette {
         self.ex_tstop();
         self.deck1.head_pos = 0;
         self.ex_rbk();
-        while !self.deck1.gap_detected {
+        // A tape without a block on it stops by itself at the end, and an empty deck never starts
+        while !self.deck1.gap_detected && !self.deck1.ready() && self.deck1.has_tape() {
             self.clock();
-            if !self.data_buffer.is_empty() {
+            if self.data_register.is_some() {
                 data_out.push(self.get_data());
                 self.strobe();
             }
@@ -315,9 +831,120 @@ impl Cassette {
     }
 }
 
+impl Peripheral for Cassette {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn get_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.data_register.is_some() {
+            status |= 1 << 2;
+        }
+
+        let deck = self.get_selected_deck();
+        if deck.ready() {
+            status |= 1 << 0;
+        }
+
+        if deck.end_of_tape() {
+            status |= 1 << 1;
+        }
+
+        if deck.write_ready() {
+            status |= 1 << 3;
+        }
+
+        if deck.gap_detected {
+            status |= 1 << 4;
+        }
+
+        if deck.has_tape() {
+            status |= 1 << 6;
+        }
+
+        status
+    }
+
+    fn get_data(&mut self) -> u8 {
+        self.data_register.map_or(0, |(data, _)| data)
+    }
+
+    fn strobe(&mut self) {
+        self.data_register = None;
+    }
+
+    fn write_data(&mut self, data: u8) {
+        let deck = self.get_selected_deck();
+        match deck.write_mode {
+            WriteMode::Block(len) => {
+                if let Some(lost) = deck.write_buf.replace(data) {
+                    warn!(
+                        "Cassette write overrun, byte {:#04x} at block offset {} was lost",
+                        lost, len
+                    );
+                }
+            }
+            _ => warn!("Cassette write of {:#04x} while not writing a block", data),
+        }
+    }
+
+    fn clock(&mut self) {
+        // Both transports keep moving, but only the selected deck is connected to the read electronics
+        let selected = self.selected_deck;
+        for id in [DeckId::Deck1, DeckId::Deck2] {
+            let deck = self.get_deck(id);
+            deck.clock();
+
+            if let Some(read_data) = deck.data_buf.take() {
+                // Searching with Sf and Sb passes data the program has no interest in,
+                // only bytes lost while reading a block count as an overrun
+                let reading = deck.stop_on_gap;
+                let index = deck.head_pos;
+                if id != selected {
+                    continue;
+                }
+                if let Some((lost, index)) = self.data_register.replace((read_data, index)) {
+                    if reading {
+                        warn!(
+                            "Cassette read overrun, byte {:#04x} at {} was lost",
+                            lost, index
+                        );
+                        self.overruns.push(ReadOverrun {
+                            deck: id,
+                            index,
+                            data: lost,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.deck1.stop();
+        self.deck2.stop();
+        self.selected_deck = DeckId::Deck1;
+        self.data_register = None;
+    }
+}
+
+impl Default for Cassette {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::DP2200::{cassette::*, datapoint::Datapoint};
+    use crate::DP2200::{
+        cassette::*,
+        datapoint::{BootMode, Datapoint},
+    };
     fn init_logger() {
         let _ = env_logger::builder()
             // Include all events in tests
@@ -332,10 +959,8 @@ mod tests {
     fn test_read_block() {
         init_logger();
         let mut cassettes = Cassette::new();
-        let tap_file = vec![
-            2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0, 5, 0, 0, 0, 1, 2, 3, 4, 5, 5, 0, 0, 0,
-        ];
-        cassettes.load(DeckId::Deck1, tap_file);
+        let tap_file = TWO_BLOCKS.to_vec();
+        cassettes.load(DeckId::Deck1, tap_file).unwrap();
         cassettes.ex_deck1();
         cassettes.ex_rbk();
         let mut data_out = Vec::new();
@@ -351,14 +976,55 @@ mod tests {
         assert_eq!(cassettes.deck1.speed, MovementSpeed::None);
     }
 
+    #[test]
+    fn test_read_with_faults() {
+        init_logger();
+        let tap_file = TWO_BLOCKS.to_vec();
+        let read = |faults: TapeFaults| {
+            let mut cassettes = Cassette::new();
+            cassettes.load(DeckId::Deck1, tap_file.clone()).unwrap();
+            cassettes.set_timing(TapeTiming::Fast);
+            cassettes.deck1.faults = Some(faults);
+            cassettes.ex_rbk();
+            let mut data_out = Vec::new();
+            while cassettes.get_status() & 1 == 0 {
+                cassettes.clock();
+                if cassettes.get_status() & (1 << 2) != 0 {
+                    data_out.push(cassettes.get_data());
+                    cassettes.strobe();
+                }
+            }
+            assert_eq!(cassettes.deck1.to_tap(), tap_file);
+            data_out
+        };
+
+        assert_eq!(read(TapeFaults::new(1)), [0xbe, 0xef]);
+        let dropout = TapeFaults {
+            dropout: 1.0,
+            ..TapeFaults::new(1)
+        };
+        assert_eq!(read(dropout), []);
+        // Without the gap the read runs on into the next block
+        let missing_gap = TapeFaults {
+            missing_gap: 1.0,
+            ..TapeFaults::new(1)
+        };
+        assert_eq!(read(missing_gap), [0xbe, 0xef, 1, 2, 3, 4, 5]);
+        let bit_flip = TapeFaults {
+            bit_flip: 1.0,
+            ..TapeFaults::new(1)
+        };
+        let flipped = read(bit_flip);
+        assert_eq!((flipped[0] ^ 0xbe).count_ones(), 1);
+        assert_eq!((flipped[1] ^ 0xef).count_ones(), 1);
+    }
+
     #[test]
     fn test_read_backwards() {
         init_logger();
         let mut cassettes = Cassette::new();
-        let tap_file = vec![
-            2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0, 5, 0, 0, 0, 1, 2, 3, 4, 5, 5, 0, 0, 0,
-        ];
-        cassettes.load(DeckId::Deck1, tap_file);
+        let tap_file = TWO_BLOCKS.to_vec();
+        cassettes.load(DeckId::Deck1, tap_file).unwrap();
         cassettes.ex_deck1();
         cassettes.ex_rbk();
         while cassettes.get_status() & (1 << 4) == 0 {
@@ -381,18 +1047,65 @@ mod tests {
     }
 
     #[test]
+    fn test_read_overrun() {
+        init_logger();
+        let mut cassettes = Cassette::new();
+        let tap_file = TWO_BLOCKS.to_vec();
+        cassettes.load(DeckId::Deck1, tap_file).unwrap();
+        cassettes.set_timing(TapeTiming::Fast);
+
+        // Skipping over a block is not an overrun
+        cassettes.ex_sf();
+        while cassettes.deck1.head_pos < 12 {
+            cassettes.clock();
+        }
+        cassettes.ex_tstop();
+        assert_eq!(cassettes.take_overruns(), vec![]);
+
+        // Read the second block without picking up any bytes
+        while cassettes.get_status() & 1 == 0 {
+            cassettes.clock();
+        }
+        cassettes.ex_rbk();
+        while cassettes.get_status() & 1 == 0 {
+            cassettes.clock();
+        }
+
+        // The last byte is still waiting, every other byte is lost
+        assert_eq!(cassettes.get_data(), 5);
+        let lost: Vec<(usize, u8)> = cassettes
+            .take_overruns()
+            .iter()
+            .map(|o| (o.index, o.data))
+            .collect();
+        assert_eq!(lost, vec![(22, 1), (23, 2), (24, 3), (25, 4)]);
+    }
+
+    // The DOS boot tape is not in the repository, put it in test_software and run
+    // the ignored tests to check it
+    fn dos_boot_tape() -> Vec<u8> {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/test_software/dosAbootVer2.tap"
+        );
+        std::fs::read(path).unwrap_or_else(|err| panic!("Could not read {}: {}", path, err))
+    }
+
+    #[test]
+    #[ignore = "needs test_software/dosAbootVer2.tap"]
     fn test_read_sector() {
-        let program = include_bytes!("../../test_software/dosAbootVer2.tap").to_vec();
+        let program = dos_boot_tape();
 
         let mut machine = Datapoint::build(&Vec::new(), 1.0);
-        machine.load_cassette(program);
+        machine.load_cassette(program, BootMode::Fast).unwrap();
     }
 
     #[test]
+    #[ignore = "needs test_software/dosAbootVer2.tap"]
     fn test_read_sector_2() {
         let mut cassettes = Cassette::new();
-        let tap_file = include_bytes!("../../test_software/dosAbootVer2.tap").to_vec();
-        cassettes.load(DeckId::Deck1, tap_file);
+        let tap_file = dos_boot_tape();
+        cassettes.load(DeckId::Deck1, tap_file).unwrap();
         let sector = cassettes.get_first_sector();
         assert_eq!(sector.len(), 512);
     }
@@ -400,9 +1113,7 @@ mod tests {
     #[test]
     fn test_read_from_cpu() {
         init_logger();
-        let tap_file = vec![
-            2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0, 5, 0, 0, 0, 1, 2, 3, 4, 5, 5, 0, 0, 0,
-        ];
+        let tap_file = TWO_BLOCKS.to_vec();
 
         let program = vec![
             "LoadImm A, 0xf0",
@@ -429,7 +1140,7 @@ mod tests {
         ];
 
         let mut machine = Datapoint::from_assembler(program, 1.0);
-        machine.databus.cassette.deck1 = CassetteDeck::new(tap_file);
+        machine.databus.cassette_mut().deck1 = CassetteDeck::new(tap_file).unwrap();
 
         machine.run();
         assert_eq!(machine.cpu.alpha_registers[1], 0xbe);
@@ -440,11 +1151,9 @@ mod tests {
     fn test_sf_gap_detect() {
         init_logger();
         let mut cassettes = Cassette::new();
-        let tap_file = vec![
-            2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0, 5, 0, 0, 0, 1, 2, 3, 4, 5, 5, 0, 0, 0,
-        ];
+        let tap_file = TWO_BLOCKS.to_vec();
 
-        cassettes.load(DeckId::Deck1, tap_file);
+        cassettes.load(DeckId::Deck1, tap_file).unwrap();
         cassettes.ex_deck1();
         cassettes.ex_sf();
         while cassettes.get_status() & (1 << 4) == 0 {
@@ -453,4 +1162,626 @@ mod tests {
 
         info!("Head pos: {}", cassettes.deck1.head_pos)
     }
+
+    #[test]
+    fn test_write_ready() {
+        init_logger();
+        let mut cassettes = Cassette::new();
+        assert_eq!(cassettes.get_status() & (1 << 3), 0);
+
+        cassettes.ex_wbk();
+        assert_ne!(cassettes.get_status() & (1 << 3), 0);
+
+        cassettes.write_data(0x42);
+        assert_eq!(cassettes.get_status() & (1 << 3), 0);
+
+        while cassettes.get_status() & (1 << 3) == 0 {
+            cassettes.clock();
+        }
+        assert_eq!(cassettes.deck1.data[1], CassetteData::Data(0x42));
+    }
+
+    #[test]
+    fn test_write_end_of_tape() {
+        init_logger();
+        let mut cassettes = Cassette::new();
+        let mut deck = CassetteDeck::from_data([CassetteData::Gap].repeat(MAX_TAPE_LENGTH - 3));
+        deck.head_pos = MAX_TAPE_LENGTH - 4;
+        cassettes.deck1 = deck;
+
+        // Waiting for the first byte erases the tape until it runs out
+        cassettes.ex_wbk();
+        while cassettes.get_status() & 1 == 0 {
+            cassettes.clock();
+        }
+        assert_eq!(cassettes.deck1.data.len(), MAX_TAPE_LENGTH);
+        assert_eq!(cassettes.deck1.write_mode, WriteMode::None);
+        assert_ne!(cassettes.get_status() & (1 << 1), 0);
+        assert_eq!(cassettes.get_status() & (1 << 3), 0);
+    }
+
+    #[test]
+    fn test_write_from_cpu() {
+        init_logger();
+        let program = vec![
+            "LoadImm A, 0xf0",
+            "Adr",
+            "Wbk",
+            "rdy1: Input",
+            "AndImm 8",
+            "JumpIf Zf, rdy1",
+            "LoadImm A, 0xbe",
+            "Write",
+            "rdy2: Input",
+            "AndImm 8",
+            "JumpIf Zf, rdy2",
+            "LoadImm A, 0xef",
+            "Write",
+            "done: Input",
+            "AndImm 1",
+            "JumpIf Zf, done",
+            "Halt",
+        ];
+
+        let mut machine = Datapoint::from_assembler(program, 1.0);
+        machine.run();
+
+        let deck = &machine.databus.cassette().deck1;
+        let mut expected = vec![
+            CassetteData::Gap,
+            CassetteData::Data(0xbe),
+            CassetteData::Data(0xef),
+        ];
+        expected.append(&mut [CassetteData::Gap].repeat(GAP_LENGTH));
+        assert_eq!(deck.data, expected);
+        assert_eq!(deck.head_pos, deck.data.len() - 1);
+        assert_eq!(deck.speed, MovementSpeed::None);
+    }
+
+    #[test]
+    fn test_write_overrun() {
+        init_logger();
+        let program = vec![
+            "LoadImm A, 0xf0",
+            "Adr",
+            "Wbk",
+            "rdy1: Input",
+            "AndImm 8",
+            "JumpIf Zf, rdy1",
+            "LoadImm A, 1",
+            "Write",
+            "LoadImm A, 2",
+            "Write",
+            "done: Input",
+            "AndImm 1",
+            "JumpIf Zf, done",
+            "Halt",
+        ];
+
+        let mut machine = Datapoint::from_assembler(program, 1.0);
+        machine.run();
+
+        let deck = &machine.databus.cassette().deck1;
+        assert_eq!(deck.data[1], CassetteData::Data(2));
+        assert_eq!(deck.data[2], CassetteData::Gap);
+    }
+
+    #[test]
+    fn test_write_then_read() {
+        init_logger();
+        let program = vec![
+            "LoadImm A, 0xf0",
+            "Adr",
+            "Rbk",
+            "skip: Input",
+            "AndImm 1",
+            "JumpIf Zf, skip",
+            "LoadImm B, 3",
+            "Wbk",
+            "rdy: Input",
+            "AndImm 8",
+            "JumpIf Zf, rdy",
+            "Load A, B",
+            "Write",
+            "SubImm 1",
+            "Load B, A",
+            "JumpIfNot Zf, rdy",
+            "done: Input",
+            "AndImm 1",
+            "JumpIf Zf, done",
+            "Halt",
+        ];
+
+        let tap_file = vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0];
+        let mut machine = Datapoint::from_assembler(program, 1.0);
+        machine
+            .databus
+            .cassette_mut()
+            .load(DeckId::Deck1, tap_file)
+            .unwrap();
+        machine.run();
+
+        let cassettes = machine.databus.cassette_mut();
+        cassettes.deck1.head_pos = 0;
+        let mut blocks = Vec::new();
+        for _ in 0..2 {
+            cassettes.ex_rbk();
+            let mut data_out = Vec::new();
+            // The gap bit is still set from the end of the write
+            while data_out.is_empty() || cassettes.get_status() & (1 << 4) == 0 {
+                cassettes.clock();
+                if cassettes.get_status() & (1 << 2) != 0 {
+                    data_out.push(cassettes.get_data());
+                    cassettes.strobe();
+                }
+            }
+            blocks.push(data_out);
+        }
+
+        assert_eq!(blocks, vec![vec![0xbe, 0xef], vec![3, 2, 1]]);
+    }
+
+    #[test]
+    fn test_rewind() {
+        init_logger();
+        let mut cassettes = Cassette::new();
+        let tap_file = TWO_BLOCKS.to_vec();
+        cassettes.load(DeckId::Deck1, tap_file).unwrap();
+        cassettes.ex_sf();
+        while cassettes.deck1.head_pos == 0 || !cassettes.deck1.end_of_tape() {
+            cassettes.clock();
+        }
+        let head_pos = cassettes.deck1.head_pos;
+        assert_eq!(head_pos, 36);
+        assert_ne!(cassettes.get_status() & (1 << 1), 0);
+
+        cassettes.ex_rewind();
+        let mut cycles = 0;
+        while cassettes.get_status() & 1 == 0 {
+            cassettes.clock();
+            assert_eq!(cassettes.get_status() & (1 << 2), 0);
+            cycles += 1;
+        }
+
+        assert_eq!(cassettes.deck1.head_pos, 0);
+        assert_ne!(cassettes.get_status() & (1 << 1), 0);
+        let timing = TapeTiming::Datasheet;
+        // The tape was moving forward, so it stops before it rewinds
+        assert_eq!(
+            cycles,
+            timing.stop_clocks()
+                + timing.start_clocks()
+                + (head_pos + 1) * 36
+                + timing.stop_clocks()
+        );
+    }
+
+    #[test]
+    fn test_start_while_stopping() {
+        init_logger();
+        let timing = TapeTiming::Datasheet;
+        let byte = timing.byte_clocks(MovementSpeed::Regular).unwrap();
+        let tap_file = TWO_BLOCKS.to_vec();
+        let mut cassettes = Cassette::new();
+        cassettes.load(DeckId::Deck1, tap_file).unwrap();
+        let run = |cassettes: &mut Cassette, clocks: usize| {
+            for _ in 0..clocks {
+                cassettes.clock();
+            }
+        };
+
+        // Reversing stops the tape before it starts the other way
+        cassettes.ex_sf();
+        run(&mut cassettes, timing.start_clocks() + 2 * byte);
+        cassettes.ex_rewind();
+        assert_eq!(
+            cassettes.deck1.ramp,
+            timing.stop_clocks() + timing.start_clocks()
+        );
+        cassettes.ex_tstop();
+        run(&mut cassettes, timing.stop_clocks());
+        assert!(cassettes.deck1.ready());
+
+        // A tape that is still stopping has to finish before it starts again
+        cassettes.ex_sf();
+        run(&mut cassettes, timing.start_clocks() + 2 * byte);
+        cassettes.ex_tstop();
+        run(&mut cassettes, 1000);
+        cassettes.ex_rbk();
+        assert_eq!(
+            cassettes.deck1.ramp,
+            timing.stop_clocks() - 1000 + timing.start_clocks()
+        );
+    }
+
+    #[test]
+    fn test_unselected_deck_moves() {
+        init_logger();
+        let mut cassettes = Cassette::new();
+        let tap_file = vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0];
+        cassettes.load(DeckId::Deck1, tap_file.clone()).unwrap();
+        cassettes.load(DeckId::Deck2, tap_file).unwrap();
+        cassettes.set_timing(TapeTiming::Fast);
+
+        cassettes.ex_rbk();
+        cassettes.ex_deck2();
+        let mut head_pos = cassettes.deck1.head_pos;
+        while cassettes.deck1.speed != MovementSpeed::None {
+            cassettes.clock();
+            // Deck 2 is stopped, and nothing read from deck 1 shows up
+            assert_eq!(cassettes.get_status() & 0b10101, 1);
+            head_pos = cassettes.deck1.head_pos;
+        }
+        assert_eq!(head_pos, 12);
+        assert_eq!(cassettes.deck2.head_pos, 0);
+
+        // Deck 1 reports its own gap when selected again
+        cassettes.ex_deck1();
+        assert_ne!(cassettes.get_status() & (1 << 4), 0);
+    }
+
+    #[test]
+    fn test_alternating_decks() {
+        init_logger();
+        let mut cassettes = Cassette::new();
+        cassettes
+            .load(DeckId::Deck1, vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0])
+            .unwrap();
+        cassettes
+            .load(DeckId::Deck2, vec![2, 0, 0, 0, 0x12, 0x34, 2, 0, 0, 0])
+            .unwrap();
+
+        // Start both decks, then read from whichever is selected
+        cassettes.ex_rbk();
+        cassettes.ex_deck2();
+        cassettes.ex_rbk();
+        let mut data_out = Vec::new();
+        while cassettes.deck1.speed != MovementSpeed::None
+            || cassettes.deck2.speed != MovementSpeed::None
+        {
+            cassettes.clock();
+            if cassettes.get_status() & (1 << 2) != 0 {
+                data_out.push(cassettes.get_data());
+                cassettes.strobe();
+            }
+        }
+
+        assert_eq!(data_out, vec![0x12, 0x34]);
+        assert_eq!(cassettes.deck1.head_pos, cassettes.deck2.head_pos);
+    }
+
+    #[test]
+    fn test_timing() {
+        init_logger();
+        let tap_file = TWO_BLOCKS.to_vec();
+
+        let mut events = Vec::new();
+        for timing in [TapeTiming::Datasheet, TapeTiming::Fast] {
+            let mut cassettes = Cassette::new();
+            cassettes.set_timing(timing);
+            cassettes.load(DeckId::Deck1, tap_file.clone()).unwrap();
+            cassettes.ex_rbk();
+
+            // (clock, head position) for every byte read and for the gap
+            let mut clocks = Vec::new();
+            let mut cycles = 0;
+            while cassettes.get_status() & 1 == 0 {
+                cassettes.clock();
+                cycles += 1;
+                let status = cassettes.get_status();
+                if status & (1 << 2) != 0 {
+                    clocks.push((cycles, cassettes.deck1.head_pos));
+                    cassettes.strobe();
+                }
+                if status & (1 << 4) != 0 && clocks.len() == 2 {
+                    clocks.push((cycles, cassettes.deck1.head_pos));
+                }
+            }
+
+            let byte = timing.byte_clocks(MovementSpeed::Regular).unwrap();
+            let start = timing.start_clocks();
+            assert_eq!(clocks[0], (start + 10 * byte, 10));
+            assert_eq!(clocks[1], (start + 11 * byte, 11));
+            assert_eq!(clocks[2], (start + 12 * byte, 12));
+            assert_eq!(cycles, start + 12 * byte + timing.stop_clocks());
+            events.push(clocks.iter().map(|(_, pos)| *pos).collect::<Vec<_>>());
+        }
+
+        // The fast tape is the same tape, only faster
+        assert_eq!(events[0], events[1]);
+        assert_eq!(
+            TapeTiming::Datasheet.byte_clocks(MovementSpeed::Regular),
+            Some(431)
+        );
+        assert_eq!(
+            TapeTiming::Fast.byte_clocks(MovementSpeed::Regular),
+            Some(53)
+        );
+    }
+
+    #[test]
+    fn test_rewind_stop_and_deck_select() {
+        init_logger();
+        let mut cassettes = Cassette::new();
+        let tap_file = vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0];
+        cassettes.load(DeckId::Deck1, tap_file).unwrap();
+        cassettes.deck1.head_pos = 10;
+
+        cassettes.ex_rewind();
+        for _ in 0..TapeTiming::Datasheet.start_clocks() + 36 * 2 {
+            cassettes.clock();
+        }
+
+        // Tstop only stops the selected deck
+        cassettes.ex_deck2();
+        cassettes.ex_tstop();
+        assert_eq!(cassettes.deck1.speed, MovementSpeed::Rewind);
+
+        cassettes.ex_deck1();
+        cassettes.ex_tstop();
+        assert_eq!(cassettes.deck1.speed, MovementSpeed::None);
+        assert_eq!(cassettes.deck1.head_pos, 8);
+    }
+
+    #[test]
+    fn test_rewind_from_cpu() {
+        init_logger();
+        let tap_file = TWO_BLOCKS.to_vec();
+
+        let program = vec![
+            "LoadImm A, 0xf0",
+            "Adr",
+            "Rbk",
+            "blk: Input",
+            "AndImm 1",
+            "JumpIf Zf, blk",
+            "Rewind",
+            "bot: Input",
+            "AndImm 1",
+            "JumpIf Zf, bot",
+            "Rbk",
+            "dat: Input",
+            "AndImm 4",
+            "JumpIf Zf, dat",
+            "Data",
+            "Input",
+            "Load B, A",
+            "Halt",
+        ];
+
+        let mut machine = Datapoint::from_assembler(program, 1.0);
+        machine
+            .databus
+            .cassette_mut()
+            .load(DeckId::Deck1, tap_file)
+            .unwrap();
+
+        machine.run();
+        assert_eq!(machine.cpu.alpha_registers[1], 0xbe);
+        assert_eq!(machine.databus.cassette().deck1.head_pos, 10);
+    }
+
+    // A tap file with two blocks, 0xbe 0xef and 1 to 5
+    const TWO_BLOCKS: [u8; 23] = [
+        2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0, 5, 0, 0, 0, 1, 2, 3, 4, 5, 5, 0, 0, 0,
+    ];
+
+    fn write_block(cassettes: &mut Cassette, block: &[u8]) {
+        cassettes.ex_wbk();
+        for byte in block {
+            while cassettes.get_status() & (1 << 3) == 0 {
+                cassettes.clock();
+            }
+            cassettes.write_data(*byte);
+        }
+        while cassettes.get_status() & 1 == 0 {
+            cassettes.clock();
+        }
+    }
+
+    #[test]
+    fn test_tap_round_trip() {
+        let tap_file = TWO_BLOCKS.to_vec();
+        let deck = CassetteDeck::new(tap_file.clone()).unwrap();
+        assert_eq!(deck.to_tap(), tap_file);
+        assert!(!deck.modified);
+    }
+
+    #[test]
+    fn test_to_tap_after_write() {
+        init_logger();
+        let mut cassettes = Cassette::new();
+        cassettes
+            .load(DeckId::Deck1, vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0])
+            .unwrap();
+        cassettes.ex_sf();
+        while cassettes.get_status() & 1 == 0 {
+            cassettes.clock();
+        }
+        write_block(&mut cassettes, &[1, 2, 3]);
+
+        assert!(cassettes.deck1.modified);
+        assert_eq!(
+            cassettes.deck1.to_tap(),
+            vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0, 3, 0, 0, 0, 1, 2, 3, 3, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_write_back_on_eject() {
+        init_logger();
+        let path = std::env::temp_dir().join("datapoint2200_test_write_back.tap");
+        std::fs::write(&path, [2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0]).unwrap();
+
+        let mut cassettes = Cassette::new();
+        cassettes.load_file(DeckId::Deck2, &path, true).unwrap();
+        cassettes.ex_deck2();
+        write_block(&mut cassettes, &[0x42]);
+        cassettes.eject(DeckId::Deck2).unwrap();
+
+        let tap_file = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(tap_file, vec![1, 0, 0, 0, 0x42, 1, 0, 0, 0]);
+        assert_eq!(cassettes.deck2.write_back, None);
+    }
+
+    #[test]
+    fn test_eject_and_insert() {
+        init_logger();
+        let tap_file = vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0];
+        let mut cassettes = Cassette::new();
+        cassettes.load(DeckId::Deck1, tap_file.clone()).unwrap();
+        assert_eq!(cassettes.get_status() & (1 << 6 | 1), 1 << 6 | 1);
+
+        // Take the tape out while it is moving
+        cassettes.ex_sf();
+        for _ in 0..10_000 {
+            cassettes.clock();
+        }
+        let tape = cassettes.eject(DeckId::Deck1).unwrap();
+        assert_eq!(tape.speed, MovementSpeed::None);
+        assert_eq!(cassettes.get_status() & (1 << 6 | 1), 0);
+
+        // Nothing moves without a tape
+        cassettes.ex_rbk();
+        assert_eq!(cassettes.deck1.speed, MovementSpeed::None);
+        assert_eq!(cassettes.get_status() & (1 << 6 | 1), 0);
+
+        // The tape goes back in where it was taken out
+        let head_pos = tape.head_pos;
+        let empty = cassettes.insert(DeckId::Deck1, tape).unwrap();
+        assert!(!empty.has_tape());
+        assert_eq!(cassettes.deck1.head_pos, head_pos);
+        assert_eq!(cassettes.get_status() & (1 << 6 | 1), 1 << 6 | 1);
+        assert_eq!(cassettes.deck1.to_tap(), tap_file);
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut cassettes = Cassette::new();
+        cassettes.set_timing(TapeTiming::Fast);
+        cassettes
+            .load(DeckId::Deck1, vec![1, 0, 0, 0, 0x11, 1, 0, 0, 0])
+            .unwrap();
+        cassettes.eject(DeckId::Deck2).unwrap();
+        cassettes.set_write_protected(DeckId::Deck1, true);
+
+        cassettes.swap().unwrap();
+        assert!(!cassettes.deck1.has_tape());
+        assert_eq!(cassettes.deck2.to_tap(), vec![1, 0, 0, 0, 0x11, 1, 0, 0, 0]);
+        assert!(cassettes.deck2.write_protected);
+        assert_eq!(cassettes.deck2.timing, TapeTiming::Fast);
+
+        cassettes.ex_deck2();
+        assert_eq!(cassettes.get_status() & (1 << 6 | 1), 1 << 6 | 1);
+        cassettes.ex_deck1();
+        assert_eq!(cassettes.get_status() & (1 << 6 | 1), 0);
+    }
+
+    #[test]
+    fn test_write_protected() {
+        init_logger();
+        let tap_file = vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0];
+        let mut cassettes = Cassette::new();
+        cassettes.load(DeckId::Deck1, tap_file.clone()).unwrap();
+        cassettes.deck1.write_protected = true;
+
+        cassettes.ex_wbk();
+        for _ in 0..10_000 {
+            cassettes.clock();
+            assert_eq!(cassettes.get_status() & (1 << 3), 0);
+        }
+        assert_eq!(cassettes.deck1.to_tap(), tap_file);
+        assert!(!cassettes.deck1.modified);
+    }
+
+    #[test]
+    fn test_shelve_image() {
+        init_logger();
+        let path = std::env::temp_dir().join("datapoint2200_test_shelve.dpt");
+        let mut cassettes = Cassette::new();
+        cassettes
+            .load(DeckId::Deck1, vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0])
+            .unwrap();
+        // Leave a short gap and a block behind the first one
+        write_block(&mut cassettes, &[0x42]);
+        let deck = &mut cassettes.deck1;
+        deck.label = "Scratch".to_string();
+        deck.notes = "Written by test_shelve_image".to_string();
+        deck.write_protected = true;
+        cassettes.save(DeckId::Deck1, &path).unwrap();
+
+        cassettes.load_file(DeckId::Deck2, &path, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (shelved, loaded) = (&cassettes.deck1, &cassettes.deck2);
+        assert_eq!(loaded.data, shelved.data);
+        assert_eq!(loaded.head_pos, shelved.head_pos);
+        assert_eq!(loaded.label, "Scratch");
+        assert_eq!(loaded.notes, shelved.notes);
+        assert!(loaded.write_protected);
+        assert_eq!(loaded.to_tap(), shelved.to_tap());
+    }
+
+    #[test]
+    fn test_parse_truncated_length() {
+        let tap_file = vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0, 5, 0];
+        assert_eq!(
+            parse_tap(&tap_file, false),
+            Err(TapError::TruncatedLength {
+                offset: 10,
+                record: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_truncated_record() {
+        let tap_file = vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0, 5, 0, 0, 0, 1, 2];
+        assert_eq!(
+            parse_tap(&tap_file, false),
+            Err(TapError::TruncatedRecord {
+                offset: 10,
+                record: 1,
+                len: 5,
+                available: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_trailing_length() {
+        let mismatch = vec![2, 0, 0, 0, 0xbe, 0xef, 3, 0, 0, 0];
+        assert_eq!(
+            parse_tap(&mismatch, true),
+            Err(TapError::LengthMismatch {
+                offset: 6,
+                record: 0,
+                leading: 2,
+                trailing: 3
+            })
+        );
+        assert!(parse_tap(&mismatch, false).is_ok());
+
+        let missing = vec![2, 0, 0, 0, 0xbe, 0xef];
+        assert_eq!(
+            parse_tap(&missing, true),
+            Err(TapError::TruncatedLength {
+                offset: 6,
+                record: 0
+            })
+        );
+        assert_eq!(
+            parse_tap(&missing, false).unwrap()[GAP_LENGTH..GAP_LENGTH + 2],
+            [CassetteData::Data(0xbe), CassetteData::Data(0xef)]
+        );
+    }
+
+    #[test]
+    fn test_load_error() {
+        let mut cassettes = Cassette::new();
+        cassettes.strict_tap = true;
+        let res = cassettes.load(DeckId::Deck1, vec![2, 0, 0, 0, 0xbe, 0xef]);
+        assert!(res.is_err());
+        assert_eq!(cassettes.deck1, CassetteDeck::blank());
+    }
 }
@@ -0,0 +1,64 @@
+// The character generator of the CRT. The screen memory holds whatever bytes the cpu
+// wrote, and this table decides what is shown for each of them.
+// The table is provisional, it is not taken from the character ROM or the manual.
+// See "The character generator" in understanding_the_machine.md.
+//
+// The display is taken to have a 64 character set, the printable ASCII codes 0x20 to 0x5f:
+// - Bit 7 does not reach the character generator, 0xc1 shows the same as 0x41.
+// - Lowercase codes fold to uppercase, 0x61 shows as 'A'. The rest of 0x60 to 0x7e fold
+//   the same way, '{' shows as '['.
+// - Control codes and DEL have no glyph and show as a blank.
+
+pub const BLANK: char = ' ';
+
+const fn glyph(code: u8) -> char {
+    let code = code & 0x7f;
+    match code {
+        0x20..=0x5f => code as char,
+        0x60..=0x7e => (code - 0x20) as char,
+        _ => BLANK,
+    }
+}
+
+const fn build() -> [char; 256] {
+    let mut table = [BLANK; 256];
+    let mut code = 0;
+    while code < 256 {
+        table[code] = glyph(code as u8);
+        code += 1;
+    }
+    table
+}
+
+pub const CHARACTER_SET: [char; 256] = build();
+
+// What the screen shows for a code
+pub fn display(code: u8) -> char {
+    CHARACTER_SET[code as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_character_set() {
+        assert_eq!(display(b'A'), 'A');
+        assert_eq!(display(b' '), ' ');
+        assert_eq!(display(b'_'), '_');
+        assert_eq!(display(b'a'), 'A');
+        assert_eq!(display(b'{'), '[');
+        assert_eq!(display(0xc1), 'A');
+        assert_eq!(display(0xe1), 'A');
+        assert_eq!(display(0x0d), BLANK);
+        assert_eq!(display(0x7f), BLANK);
+        assert_eq!(display(0xff), BLANK);
+
+        // Only 64 different glyphs, and every one of them printable
+        let mut glyphs = CHARACTER_SET.to_vec();
+        glyphs.sort_unstable();
+        glyphs.dedup();
+        assert_eq!(glyphs.len(), 64);
+        assert!(glyphs.iter().all(|c| (' '..='_').contains(c)));
+    }
+}
@@ -8,8 +8,8 @@ pub struct Clock {
 
 const CYCLE_TIME_NS: u128 = 1_600;
 const INTR_TIME_NS: u128 = 1_000_000;
-// 153.6kHz clock @ 1600 ns
-const DATABUS_CLOCK_NS: u128 = 156_300 / CYCLE_TIME_NS;
+// 153.6kHz databus clock
+pub const DATABUS_CLOCK_NS: u128 = 1_000_000_000 / 153_600;
 
 impl Clock {
     pub fn build(time_scale: f32) -> Clock {
@@ -37,7 +37,10 @@ impl Clock {
             cpu.interrupt();
         }
 
-        if self.check_trigger(DATABUS_CLOCK_NS, num_clocks) {
+        // One databus clock is about four cpu cycles, so an instruction can span none or several
+        let end_time = self.emulated_time_ns + CYCLE_TIME_NS * num_clocks;
+        let databus_clocks = end_time / DATABUS_CLOCK_NS - self.emulated_time_ns / DATABUS_CLOCK_NS;
+        for _ in 0..databus_clocks {
             databus.clock();
         }
 
@@ -1,6 +1,5 @@
 use crate::DP2200::instruction::*;
-use log::{error, info, trace};
-use std::sync::mpsc::Receiver;
+use log::error;
 
 use super::databus::{Databus, DatabusMode};
 
@@ -37,6 +36,15 @@ impl Cpu {
             instruction_register: Instruction::unknown(),
         }
     }
+
+    // The state after RESTART. Memory is not cleared, the restart logic loads the boot block on top of it.
+    pub fn reset(&mut self) {
+        *self = Cpu {
+            memory: self.memory,
+            ..Cpu::build()
+        };
+    }
+
     fn get_from_mem(&mut self) -> Option<u8> {
         let res = self.memory.get(self.program_counter as usize)?;
         self.program_counter += 1;
@@ -598,7 +606,7 @@ mod tests {
     #[test]
     fn test_load_reg_to_reg_inst_neg() {
         let mut machine = Datapoint::from_assembler(vec!["Load B, A", "Halt"], 1.0);
-        machine.cpu.alpha_registers[0] = (-10_i8 as u8);
+        machine.cpu.alpha_registers[0] = -10_i8 as u8;
         machine.run();
 
         assert_eq!(machine.cpu.alpha_registers[1] as i8, -10);
@@ -0,0 +1,267 @@
+// CTOS treats a cassette as a file system. Every file starts with a file marker,
+// and is made of numeric (or symbolic) records. A program is loaded as a run of
+// records at increasing addresses, and a record without payload ends the load,
+// with the address to start at in its header.
+
+use super::{
+    cassette::{read_tap_blocks, write_tap_blocks, TapError},
+    tape::{DataRecord, Record, Tape},
+};
+
+// The largest payload CTOS writes, so a record is 256 bytes on tape
+pub const MAX_PAYLOAD: usize = 248;
+
+// The recorded part of the tape ends with a marker for this file
+pub const END_MARKER: u8 = 0x20;
+
+// Framing of the text in symbolic records, borrowed from the DOS text files and not checked
+// against a real CTOS tape, see "Symbolic records" in understanding_the_machine.md
+pub const END_OF_LINE: u8 = 0x0d;
+pub const SPACES: u8 = 0x09;
+pub const END_OF_TEXT: u8 = 0x03;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapeFile {
+    pub number: u8,
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RecordCounts {
+    pub numeric: usize,
+    pub symbolic: usize,
+    pub other: usize,
+    // Payload bytes in the numeric and symbolic records
+    pub bytes: usize,
+}
+
+// Best effort at turning the payload of symbolic records into text, for looking at.
+// Lines end with END_OF_LINE, SPACES is followed by a count of spaces,
+// and nothing after END_OF_TEXT is part of the file. It is not known what bit 7 means
+// in a symbolic record, so a byte with it set is shown as \xNN instead of being guessed at.
+pub fn decode_symbolic(data: &[u8]) -> String {
+    let mut text = String::new();
+    let mut bytes = data.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            END_OF_TEXT => break,
+            END_OF_LINE => text.push('\n'),
+            SPACES => {
+                let count = bytes.next().copied().unwrap_or(0);
+                text.extend(std::iter::repeat(' ').take(count as usize));
+            }
+            b if b & 0x80 != 0 => text.push_str(&format!("\\x{:02x}", b)),
+            b => text.push(char::from(b)),
+        }
+    }
+    text
+}
+
+impl TapeFile {
+    // Split an image into numeric records at consecutive addresses, ended by
+    // an empty record pointing at entry
+    pub fn from_image(number: u8, address: u16, entry: u16, image: &[u8]) -> TapeFile {
+        let mut records: Vec<Record> = image
+            .chunks(MAX_PAYLOAD)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let address = address.wrapping_add((i * MAX_PAYLOAD) as u16);
+                Record::Numeric(DataRecord::new(address, chunk.to_vec()))
+            })
+            .collect();
+        records.push(Record::Numeric(DataRecord::new(entry, Vec::new())));
+
+        TapeFile { number, records }
+    }
+
+    // Read the records of a file from a .tap made by to_tap.
+    // The records are copied as they are, so nothing is lost moving a file between tapes.
+    pub fn from_tap(number: u8, tap_file: &[u8], strict: bool) -> Result<TapeFile, TapError> {
+        let records = read_tap_blocks(tap_file, strict)?
+            .iter()
+            .map(|block| Record::parse(block, false))
+            .filter(|record| !matches!(record, Record::FileMarker(_)))
+            .collect();
+
+        Ok(TapeFile { number, records })
+    }
+
+    // The records of the file, without the file marker
+    pub fn to_tap(&self) -> Vec<u8> {
+        let blocks: Vec<Vec<u8>> = self.records.iter().map(|r| r.to_bytes()).collect();
+        write_tap_blocks(&blocks)
+    }
+
+    pub fn counts(&self) -> RecordCounts {
+        let mut counts = RecordCounts::default();
+        for record in self.records.iter() {
+            match record {
+                Record::Numeric(data) => {
+                    counts.numeric += 1;
+                    counts.bytes += data.payload.len();
+                }
+                Record::Symbolic(data) => {
+                    counts.symbolic += 1;
+                    counts.bytes += data.payload.len();
+                }
+                _ => counts.other += 1,
+            }
+        }
+        counts
+    }
+
+    pub fn numeric(&self) -> impl Iterator<Item = &DataRecord> {
+        self.records.iter().filter_map(|record| match record {
+            Record::Numeric(data) => Some(data),
+            _ => None,
+        })
+    }
+
+    pub fn symbolic(&self) -> impl Iterator<Item = &DataRecord> {
+        self.records.iter().filter_map(|record| match record {
+            Record::Symbolic(data) => Some(data),
+            _ => None,
+        })
+    }
+
+    // The payloads of the numeric records in tape order
+    pub fn numeric_image(&self) -> Vec<u8> {
+        self.numeric()
+            .flat_map(|r| r.payload.iter().copied())
+            .collect()
+    }
+
+    pub fn symbolic_data(&self) -> Vec<u8> {
+        self.symbolic()
+            .flat_map(|r| r.payload.iter().copied())
+            .collect()
+    }
+
+    pub fn text(&self) -> String {
+        decode_symbolic(&self.symbolic_data())
+    }
+
+    // Where the last load of the file starts
+    pub fn entry(&self) -> Option<u16> {
+        self.numeric()
+            .filter(|r| r.payload.is_empty())
+            .last()
+            .map(|r| r.address)
+    }
+}
+
+impl Tape {
+    // Every file on the tape, in tape order.
+    // Records in front of the first file marker, other than the boot block, are skipped.
+    pub fn files(&self) -> Vec<TapeFile> {
+        let mut files: Vec<TapeFile> = Vec::new();
+        for (file, record) in self.with_files() {
+            match (record, files.last_mut()) {
+                (Record::FileMarker(number), _) => files.push(TapeFile {
+                    number: *number,
+                    records: Vec::new(),
+                }),
+                (_, Some(last)) if file.is_some() => last.records.push(record.clone()),
+                _ => {}
+            }
+        }
+        files
+    }
+
+    pub fn file(&self, number: u8) -> Option<TapeFile> {
+        self.files().into_iter().find(|f| f.number == number)
+    }
+
+    // A tape with the boot block, then every file behind its marker.
+    // The end marker is added unless the last file already is one.
+    pub fn build(boot: Vec<u8>, files: Vec<TapeFile>) -> Tape {
+        let mut records = vec![Record::Boot(boot)];
+        let ended = files.last().map_or(false, |f| f.number >= END_MARKER);
+        for file in files {
+            records.push(Record::FileMarker(file.number));
+            records.extend(file.records);
+        }
+        if !ended {
+            records.push(Record::FileMarker(END_MARKER));
+        }
+
+        Tape { records }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_games() {
+        let tape = Tape::parse(include_bytes!("../../Games.tap"), true).unwrap();
+        let files = tape.files();
+        let numbers: Vec<u8> = files.iter().map(|f| f.number).collect();
+
+        assert_eq!(numbers, [(0..=12).collect(), vec![0x20, 0x7f]].concat());
+        assert_eq!(
+            files[1].counts(),
+            RecordCounts {
+                numeric: 1,
+                symbolic: 0,
+                other: 0,
+                bytes: 116
+            }
+        );
+        assert_eq!(files[4].entry(), Some(0x0200));
+        assert!(files[13].records.is_empty());
+    }
+
+    #[test]
+    fn test_rebuild_games() {
+        let tap_file = include_bytes!("../../Games.tap");
+        let tape = Tape::parse(tap_file, true).unwrap();
+
+        // Move every file through its own .tap and back
+        let files = tape
+            .files()
+            .iter()
+            .map(|f| TapeFile::from_tap(f.number, &f.to_tap(), true).unwrap())
+            .collect();
+        let rebuilt = Tape::build(tape.boot().unwrap().to_vec(), files);
+
+        assert_eq!(rebuilt.to_tap(), tap_file.to_vec());
+    }
+
+    #[test]
+    fn test_from_image() {
+        let image: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let file = TapeFile::from_image(3, 0x0200, 0x0203, &image);
+        let addresses: Vec<u16> = file.numeric().map(|r| r.address).collect();
+
+        assert_eq!(addresses, [0x0200, 0x02f8, 0x0203]);
+        assert_eq!(file.numeric_image(), image);
+        assert_eq!(file.entry(), Some(0x0203));
+        assert!(file.numeric().all(|r| r.checks_ok()));
+
+        let tape = Tape::build(vec![0; 512], vec![file.clone()]);
+        let end = TapeFile {
+            number: END_MARKER,
+            records: Vec::new(),
+        };
+        assert_eq!(tape.files(), [file, end]);
+    }
+
+    #[test]
+    fn test_decode_symbolic() {
+        let data = [
+            b'A',
+            SPACES,
+            4,
+            b'B',
+            END_OF_LINE,
+            END_OF_LINE,
+            b'C',
+            END_OF_LINE,
+        ];
+        assert_eq!(decode_symbolic(&data), "A    B\n\nC\n");
+        assert_eq!(decode_symbolic(&[b'X', END_OF_TEXT, b'Y']), "X");
+        assert_eq!(decode_symbolic(&[b'X', 0xc1, b'Y']), "X\\xc1Y");
+    }
+}
@@ -1,11 +1,8 @@
-use std::{
-    sync::mpsc::{channel, Receiver, Sender, TryRecvError},
-    sync::{Arc, RwLock},
-};
+use std::collections::BTreeMap;
 
-use log::info;
+use log::warn;
 
-use crate::DP2200::{cassette::Cassette, screen::Screen};
+use crate::DP2200::{cassette::Cassette, peripheral::Peripheral, screen::Screen};
 use crate::DP2200::{
     cassette::CASSETTE_ADDR,
     instruction::{Instruction, InstructionType},
@@ -21,60 +18,94 @@ pub enum DatabusMode {
     Status,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Databus {
     pub selected_addr: u8,
     pub selected_mode: DatabusMode,
-    pub screen: Screen,
-    pub keyboard: Keyboard,
-    pub cassette: Cassette,
+    // Several devices can share an address, like the screen and the keyboard
+    peripherals: BTreeMap<u8, Vec<Box<dyn Peripheral>>>,
 }
 
 impl Databus {
     pub fn build() -> Databus {
-        Databus {
+        let mut databus = Databus {
             selected_addr: 0,
             selected_mode: DatabusMode::Status,
-            screen: Screen::new(),
-            keyboard: Keyboard::new(),
-            cassette: Cassette::new(),
-        }
+            peripherals: BTreeMap::new(),
+        };
+
+        databus.attach(CASSETTE_ADDR, Box::new(Cassette::new()));
+        databus.attach(SCREEN_ADDR, Box::new(Screen::new()));
+        databus.attach(KEYBOARD_ADDR, Box::new(Keyboard::new()));
+        databus
     }
-    fn read_status(&mut self) -> u8 {
-        if self.selected_mode == DatabusMode::Status {
-            let mut status = 0;
-            if self.selected_addr == CASSETTE_ADDR {
-                status |= self.cassette.get_status();
-            }
 
-            if self.selected_addr == SCREEN_ADDR {
-                status |= self.screen.get_status();
-            }
+    pub fn attach(&mut self, addr: u8, peripheral: Box<dyn Peripheral>) {
+        self.peripherals.entry(addr).or_default().push(peripheral);
+    }
 
-            if self.selected_addr == KEYBOARD_ADDR {
-                status |= self.keyboard.get_status();
-            }
+    pub fn detach(&mut self, addr: u8) -> Vec<Box<dyn Peripheral>> {
+        self.peripherals.remove(&addr).unwrap_or_default()
+    }
+
+    // Find the first device of type T attached at addr
+    pub fn device<T: Peripheral>(&self, addr: u8) -> Option<&T> {
+        self.peripherals
+            .get(&addr)?
+            .iter()
+            .find_map(|p| p.as_any().downcast_ref::<T>())
+    }
+
+    pub fn device_mut<T: Peripheral>(&mut self, addr: u8) -> Option<&mut T> {
+        self.peripherals
+            .get_mut(&addr)?
+            .iter_mut()
+            .find_map(|p| p.as_any_mut().downcast_mut::<T>())
+    }
+
+    pub fn screen(&self) -> &Screen {
+        self.device(SCREEN_ADDR).expect("No screen attached")
+    }
+
+    pub fn screen_mut(&mut self) -> &mut Screen {
+        self.device_mut(SCREEN_ADDR).expect("No screen attached")
+    }
+
+    pub fn keyboard(&self) -> &Keyboard {
+        self.device(KEYBOARD_ADDR).expect("No keyboard attached")
+    }
+
+    pub fn keyboard_mut(&mut self) -> &mut Keyboard {
+        self.device_mut(KEYBOARD_ADDR)
+            .expect("No keyboard attached")
+    }
+
+    pub fn cassette(&self) -> &Cassette {
+        self.device(CASSETTE_ADDR).expect("No cassette attached")
+    }
 
-            return status;
+    pub fn cassette_mut(&mut self) -> &mut Cassette {
+        self.device_mut(CASSETTE_ADDR)
+            .expect("No cassette attached")
+    }
+
+    fn selected(&mut self) -> impl Iterator<Item = &mut Box<dyn Peripheral>> {
+        self.peripherals
+            .get_mut(&self.selected_addr)
+            .into_iter()
+            .flatten()
+    }
+
+    fn read_status(&mut self) -> u8 {
+        if self.selected_mode == DatabusMode::Status {
+            return self.selected().fold(0, |status, p| status | p.get_status());
         }
         0
     }
 
     fn read_data(&mut self) -> u8 {
         if self.selected_mode == DatabusMode::Data {
-            let mut data = 0;
-            if self.selected_addr == CASSETTE_ADDR {
-                data |= self.cassette.get_data();
-            }
-
-            if self.selected_addr == SCREEN_ADDR {
-                data |= self.screen.get_data();
-            }
-
-            if self.selected_addr == KEYBOARD_ADDR {
-                data |= self.keyboard.get_data();
-            }
-            return data;
+            return self.selected().fold(0, |data, p| data | p.get_data());
         }
         0
     }
@@ -84,50 +115,34 @@ impl Databus {
     }
 
     pub fn write_data(&mut self, data: u8) {
-        if self.selected_addr == CASSETTE_ADDR {
-            self.cassette.write_data(data)
-        }
-
-        if self.selected_addr == SCREEN_ADDR {
-            self.screen.write_data(data);
-        }
-
-        if self.selected_addr == KEYBOARD_ADDR {
-            self.keyboard.write_data(data);
-        }
+        self.selected().for_each(|p| p.write_data(data));
     }
 
+    // Every device is clocked, selected or not. A tape keeps moving when the cpu talks to the screen.
     pub fn clock(&mut self) {
-        if self.selected_addr == CASSETTE_ADDR {
-            self.cassette.clock();
-        }
-
-        if self.selected_addr == SCREEN_ADDR {
-            self.screen.clock();
-        }
-
-        if self.selected_addr == KEYBOARD_ADDR {
-            self.keyboard.clock();
-        }
+        self.peripherals
+            .values_mut()
+            .flatten()
+            .for_each(|p| p.clock());
 
         self.read_status();
     }
 
     pub fn strobe(&mut self) {
         if self.selected_mode == DatabusMode::Data {
-            if self.selected_addr == KEYBOARD_ADDR {
-                self.keyboard.strobe();
-            }
-            if self.selected_addr == SCREEN_ADDR {
-                self.screen.strobe();
-            }
-
-            if self.selected_addr == CASSETTE_ADDR {
-                self.cassette.strobe();
-            }
+            self.selected().for_each(|p| p.strobe());
         }
     }
 
+    pub fn reset(&mut self) {
+        self.peripherals
+            .values_mut()
+            .flatten()
+            .for_each(|p| p.reset());
+        self.selected_addr = 0;
+        self.selected_mode = DatabusMode::Status;
+    }
+
     pub fn set_addr(&mut self, addr: u8) {
         self.selected_addr = addr;
         self.selected_mode = DatabusMode::Status;
@@ -145,40 +160,179 @@ impl Databus {
             InstructionType::Write => {
                 self.write_data(data);
             }
-            InstructionType::Com1 => {
-                if self.selected_addr == SCREEN_ADDR {
-                    self.screen.control_word(data);
-                }
-            }
-            InstructionType::Com2 => {
-                if self.selected_addr == SCREEN_ADDR {
-                    self.screen.set_horizontal(data);
-                }
-            }
-            InstructionType::Com3 => {
-                if self.selected_addr == SCREEN_ADDR {
-                    self.screen.set_vertical(data);
-                }
-            }
-            InstructionType::Com4 => todo!(),
+            InstructionType::Com1 => self.selected().for_each(|p| p.com1(data)),
+            InstructionType::Com2 => self.selected().for_each(|p| p.com2(data)),
+            InstructionType::Com3 => self.selected().for_each(|p| p.com3(data)),
+            InstructionType::Com4 => self.selected().for_each(|p| p.com4(data)),
             InstructionType::Beep => {}
             InstructionType::Click => {}
-            InstructionType::Deck1 => self.cassette.ex_deck1(),
-            InstructionType::Deck2 => self.cassette.ex_deck2(),
-            InstructionType::Rbk => self.cassette.ex_rbk(),
-            InstructionType::Wbk => todo!(),
-            InstructionType::Bsp => self.cassette.ex_bsp(),
-            InstructionType::Sf => self.cassette.ex_sf(),
-            InstructionType::Sb => self.cassette.ex_sb(),
-            InstructionType::Rewind => todo!(),
-            InstructionType::Tstop => self.cassette.ex_tstop(),
+            InstructionType::Deck1
+            | InstructionType::Deck2
+            | InstructionType::Rbk
+            | InstructionType::Wbk
+            | InstructionType::Bsp
+            | InstructionType::Sf
+            | InstructionType::Sb
+            | InstructionType::Rewind
+            | InstructionType::Tstop => self.cassette_command(inst.instruction_type),
             InstructionType::Halt => {}
             _ => {}
         }
     }
 
+    // The tape commands go to the cassette whatever address is selected.
+    // Without a cassette attached there is nothing to do them, like on a machine without the decks.
+    fn cassette_command(&mut self, command: InstructionType) {
+        let Some(cassette) = self.device_mut::<Cassette>(CASSETTE_ADDR) else {
+            warn!("{:?} with no cassette attached, ignored", command);
+            return;
+        };
+        match command {
+            InstructionType::Deck1 => cassette.ex_deck1(),
+            InstructionType::Deck2 => cassette.ex_deck2(),
+            InstructionType::Rbk => cassette.ex_rbk(),
+            InstructionType::Wbk => cassette.ex_wbk(),
+            InstructionType::Bsp => cassette.ex_bsp(),
+            InstructionType::Sf => cassette.ex_sf(),
+            InstructionType::Sb => cassette.ex_sb(),
+            InstructionType::Rewind => cassette.ex_rewind(),
+            InstructionType::Tstop => cassette.ex_tstop(),
+            _ => {}
+        }
+    }
+
     pub fn update(&mut self) {
         self.read_data();
         self.read_status();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+
+    use super::*;
+    use crate::DP2200::{cassette::DeckId, datapoint::Datapoint, keyboard::DpKey};
+
+    #[derive(Debug, Default)]
+    struct Latch {
+        data: u8,
+        com: [u8; 4],
+        strobes: usize,
+    }
+
+    impl Peripheral for Latch {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+
+        fn get_status(&mut self) -> u8 {
+            1
+        }
+
+        fn get_data(&mut self) -> u8 {
+            self.data
+        }
+
+        fn write_data(&mut self, data: u8) {
+            self.data = data;
+        }
+
+        fn strobe(&mut self) {
+            self.strobes += 1;
+        }
+
+        fn com1(&mut self, data: u8) {
+            self.com[0] = data;
+        }
+
+        fn com4(&mut self, data: u8) {
+            self.com[3] = data;
+        }
+    }
+
+    #[test]
+    fn test_attach_peripheral() {
+        let program = vec![
+            "LoadImm A, 0x42",
+            "Adr",
+            "wait: Input",
+            "AndImm 1",
+            "JumpIf Zf, wait",
+            "LoadImm A, 0x5a",
+            "Write",
+            "LoadImm A, 3",
+            "Com1",
+            "LoadImm A, 4",
+            "Com4",
+            "Data",
+            "Input",
+            "Load B, A",
+            "Halt",
+        ];
+
+        let mut machine = Datapoint::from_assembler(program, 1.0);
+        machine.databus.attach(0x42, Box::new(Latch::default()));
+        machine.run();
+
+        let latch = machine.databus.device::<Latch>(0x42).unwrap();
+        assert_eq!(machine.cpu.alpha_registers[1], 0x5a);
+        assert_eq!(latch.com, [3, 0, 0, 4]);
+        assert_eq!(latch.strobes, 1);
+    }
+
+    #[test]
+    fn test_tape_commands_without_cassette() {
+        let program = vec![
+            "Deck1",
+            "Sf",
+            "Rbk",
+            "Rewind",
+            "Tstop",
+            "LoadImm B, 7",
+            "Halt",
+        ];
+
+        // Another device in place of the cassette, the commands are ignored
+        let mut machine = Datapoint::from_assembler(program, 1.0);
+        machine.databus.detach(CASSETTE_ADDR);
+        machine
+            .databus
+            .attach(CASSETTE_ADDR, Box::new(Latch::default()));
+        machine.run();
+
+        assert_eq!(machine.cpu.alpha_registers[1], 7);
+        assert!(machine.databus.device::<Cassette>(CASSETTE_ADDR).is_none());
+    }
+
+    #[test]
+    fn test_shared_address() {
+        let mut databus = Databus::build();
+        databus.keyboard_mut().keydown(DpKey::Char(b'A'));
+        databus.set_addr(SCREEN_ADDR);
+
+        // Screen write ready and keyboard read ready are both visible
+        assert_eq!(databus.read_bus(), 0b11);
+    }
+
+    #[test]
+    fn test_clock_unselected() {
+        let mut databus = Databus::build();
+        let cassette = databus.cassette_mut();
+        cassette
+            .load(DeckId::Deck1, vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0])
+            .unwrap();
+        cassette.ex_sf();
+
+        databus.set_addr(SCREEN_ADDR);
+        for _ in 0..10_000 {
+            databus.clock();
+        }
+        // 3072 clocks to get up to speed, then 431 clocks per byte
+        assert_eq!(databus.cassette_mut().get_deck(DeckId::Deck1).head_pos, 16);
+    }
+}
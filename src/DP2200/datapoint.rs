@@ -1,19 +1,10 @@
 use log::{error, info, trace};
 
-use std::{collections::VecDeque, sync::mpsc::channel};
-
-use crate::DP2200::{
-    assembler::assemble,
-    clock::Clock,
-    cpu::Cpu,
-    databus::{Databus, DatabusMode},
-    screen::Screen,
-};
+use crate::DP2200::{assembler::assemble, clock::Clock, cpu::Cpu, databus::Databus};
 
 use super::{
-    cassette::{Cassette, DeckId},
-    instruction::Instruction,
-    keyboard::Keyboard,
+    cassette::{Cassette, DeckId, LoadError, ReadOverrun, CASSETTE_ADDR},
+    peripheral::Peripheral,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq)]
@@ -23,12 +14,49 @@ pub enum DataPointRunStatus {
     Halted,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+    // Rewind the tape and load the first block in emulated time, like the RESTART key
+    Restart,
+    // Copy the first block straight into memory, without spending any emulated time
+    Fast,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartStage {
+    Rewind,
+    Read,
+}
+
+// The restart logic runs instead of the cpu until the first block is in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Restart {
+    pub deck: DeckId,
+    pub stage: RestartStage,
+    // Where the next byte from the tape goes
+    pub address: usize,
+}
+
+// Things a program did wrong that the real machine would not tell anyone about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Diagnostic {
+    // A byte from the tape was lost because the program did not read it in time.
+    // address is the instruction that was running when the next byte arrived.
+    TapeOverrun {
+        address: u16,
+        time_ns: u128,
+        overrun: ReadOverrun,
+    },
+}
+
 #[derive(Debug)]
 pub struct Datapoint {
     pub cpu: Cpu,
     pub clock: Clock,
     pub databus: Databus,
     pub breakpoints: Vec<u16>,
+    pub restart: Option<Restart>,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl Datapoint {
@@ -38,6 +66,8 @@ impl Datapoint {
             cpu: Cpu::build(),
             clock: Clock::build(time_scale),
             databus: Databus::build(),
+            restart: None,
+            diagnostics: Vec::new(),
         };
         res.load_program(program);
 
@@ -64,10 +94,90 @@ impl Datapoint {
         self.breakpoints = Vec::new();
     }
 
-    pub fn load_cassette(&mut self, tap_file: Vec<u8>) {
-        self.databus.cassette.load(DeckId::Deck1, tap_file);
-        let program = self.databus.cassette.get_first_sector();
-        self.load_program(&program);
+    pub fn load_cassette(&mut self, tap_file: Vec<u8>, boot: BootMode) -> Result<(), LoadError> {
+        let cassette = self.databus.cassette_mut();
+        cassette.load(DeckId::Deck1, tap_file)?;
+        match boot {
+            BootMode::Restart => self.restart(DeckId::Deck1),
+            BootMode::Fast => {
+                let program = self.databus.cassette_mut().get_first_sector();
+                self.load_program(&program);
+            }
+        }
+        Ok(())
+    }
+
+    // Press RESTART. The deck is rewound and its first block is loaded at address 0,
+    // then the cpu starts from there. Normally this is deck 1, unless deck 2 is selected for booting.
+    pub fn restart(&mut self, deck: DeckId) {
+        self.cpu.reset();
+        self.databus.reset();
+
+        let cassette = self.databus.cassette_mut();
+        cassette.selected_deck = deck;
+        cassette.ex_rewind();
+        self.restart = Some(Restart {
+            deck,
+            stage: RestartStage::Rewind,
+            address: 0,
+        });
+    }
+
+    fn collect_diagnostics(&mut self, address: u16) {
+        let overruns = match self.databus.device_mut::<Cassette>(CASSETTE_ADDR) {
+            Some(cassette) => cassette.take_overruns(),
+            None => Vec::new(),
+        };
+        for overrun in overruns {
+            self.diagnostics.push(Diagnostic::TapeOverrun {
+                address,
+                time_ns: self.clock.emulated_time_ns,
+                overrun,
+            });
+        }
+    }
+
+    // Clock the cassette for one cycle of the restart logic
+    fn restart_step(&mut self, mut restart: Restart) {
+        self.clock.ticks(1, &mut self.cpu, &mut self.databus);
+
+        let cassette = self.databus.cassette_mut();
+        // An empty deck never gets ready, so there is nothing to wait for
+        if !cassette.get_deck(restart.deck).has_tape() {
+            error!("No tape in {:?} to restart from", restart.deck);
+            self.cpu.halted = true;
+            self.restart = None;
+            return;
+        }
+        let status = cassette.get_status();
+        match restart.stage {
+            RestartStage::Rewind => {
+                if status & 1 != 0 {
+                    cassette.ex_rbk();
+                    restart.stage = RestartStage::Read;
+                }
+            }
+            RestartStage::Read => {
+                if status & (1 << 2) != 0 {
+                    let data = cassette.get_data();
+                    cassette.strobe();
+                    match self.cpu.memory.get_mut(restart.address) {
+                        Some(cell) => *cell = data,
+                        None => error!("Boot block does not fit in memory, dropping {:#04x}", data),
+                    }
+                    restart.address += 1;
+                } else if status & 1 != 0 {
+                    // The deck stops at the gap after the block
+                    info!("Booted {} bytes from {:?}", restart.address, restart.deck);
+                    self.cpu.reset();
+                    self.databus.reset();
+                    self.restart = None;
+                    return;
+                }
+            }
+        }
+
+        self.restart = Some(restart);
     }
 
     pub fn update(&mut self, delta_time_ms: f64) -> DataPointRunStatus {
@@ -78,7 +188,15 @@ impl Datapoint {
 
         let goal_time = self.clock.emulated_time_ns + (delta_time_ms * 1_000_000.0) as u128;
 
+        while let Some(restart) = self.restart {
+            self.restart_step(restart);
+            if self.clock.emulated_time_ns >= goal_time {
+                return DataPointRunStatus::Ok;
+            }
+        }
+
         loop {
+            let address = self.cpu.program_counter;
             let inst = self.cpu.fetch_instruction();
             if inst.is_none() {
                 error!(
@@ -95,6 +213,7 @@ impl Datapoint {
                 &mut self.databus,
             );
 
+            self.collect_diagnostics(address);
             self.cpu.execute_instruction(&mut self.databus);
 
             self.databus.update();
@@ -114,6 +233,12 @@ impl Datapoint {
     }
 
     pub fn single_step(&mut self) -> DataPointRunStatus {
+        if let Some(restart) = self.restart {
+            self.restart_step(restart);
+            return DataPointRunStatus::Ok;
+        }
+
+        let address = self.cpu.program_counter;
         let inst = self.cpu.fetch_instruction();
         if inst.is_none() {
             error!(
@@ -129,6 +254,7 @@ impl Datapoint {
             &mut self.cpu,
             &mut self.databus,
         );
+        self.collect_diagnostics(address);
         self.cpu.execute_instruction(&mut self.databus);
 
         self.databus.update();
@@ -160,6 +286,7 @@ impl Datapoint {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::DP2200::{cassette::TapeTiming, clock::DATABUS_CLOCK_NS, databus::DatabusMode};
 
     #[test]
     fn test_select_addr() {
@@ -180,6 +307,152 @@ mod tests {
         machine.run();
         let db = machine.databus;
         assert_eq!(db.selected_addr, 0xe1);
-        assert_eq!(db.screen.buffer[0][0], 'Z');
+        assert_eq!(db.screen().buffer[0][0], b'Z');
+    }
+
+    #[test]
+    fn test_banner() {
+        // Polls write ready before every character, or some would be lost
+        let program = include_str!("../../test_software/banner.asm");
+
+        let mut machine = Datapoint::from_assembler(program.lines().collect(), 1.0);
+        machine.run();
+        let screen = machine.databus.screen();
+        let line: String = (20..32).map(|c| screen.get_char(6, c)).collect();
+        assert_eq!(line, "HELLO WORLD!");
+    }
+
+    fn boot_tape(program: Vec<&str>) -> Vec<u8> {
+        let block = assemble(program).unwrap();
+        let len = (block.len() as u32).to_le_bytes();
+        [&len[..], &block, &len, &[1, 0, 0, 0, 0xff, 1, 0, 0, 0]].concat()
+    }
+
+    #[test]
+    fn test_restart() {
+        let tap_file = boot_tape(vec!["LoadImm B, 0x42", "Halt"]);
+
+        let mut machine = Datapoint::build(&[], 1.0);
+        machine.load_cassette(tap_file, BootMode::Restart).unwrap();
+        machine.cpu.memory[3] = 0x55;
+        machine.run();
+
+        assert_eq!(machine.restart, None);
+        assert_eq!(machine.cpu.alpha_registers[1], 0x42);
+        // Memory after the block is left alone
+        assert_eq!(machine.cpu.memory[3], 0x55);
+        // The head stops on the first gap after the boot block
+        let head_pos = machine
+            .databus
+            .cassette_mut()
+            .get_deck(DeckId::Deck1)
+            .head_pos;
+        assert_eq!(head_pos, 10 + 3);
+
+        // Rewind from the beginning of the tape, then read up to the gap after the block,
+        // and the tape has to start and stop for both
+        let timing = TapeTiming::Datasheet;
+        let ramps = 2 * (timing.start_clocks() + timing.stop_clocks());
+        let clocks = ramps + 36 + 13 * 431;
+        let boot_time = clocks as u128 * DATABUS_CLOCK_NS;
+        let run_time = machine.clock.emulated_time_ns;
+        assert!(run_time > boot_time && run_time < boot_time + 4 * DATABUS_CLOCK_NS);
+    }
+
+    #[test]
+    fn test_restart_deck2() {
+        let tap_file = boot_tape(vec!["LoadImm C, 0x24", "Halt"]);
+
+        let mut machine = Datapoint::build(&[], 1.0);
+        let cassette = machine.databus.cassette_mut();
+        cassette.load(DeckId::Deck2, tap_file).unwrap();
+        cassette.get_deck(DeckId::Deck2).head_pos = 12;
+        machine.restart(DeckId::Deck2);
+        machine.run();
+
+        assert_eq!(machine.cpu.alpha_registers[2], 0x24);
+        assert_eq!(machine.databus.cassette().selected_deck, DeckId::Deck1);
+    }
+
+    #[test]
+    fn test_restart_empty_deck() {
+        let mut machine = Datapoint::build(&[], 1.0);
+        machine.restart(DeckId::Deck1);
+        machine.run();
+
+        assert!(machine.cpu.halted);
+        assert!(machine.restart.is_none());
+    }
+
+    #[test]
+    fn test_tape_overrun() {
+        let program = vec![
+            "LoadImm A, 0xf0",
+            "Adr",
+            "Rbk",
+            // Wait for the whole block to pass without reading it
+            "wait: Input",
+            "AndImm 16",
+            "JumpIf Zf, wait",
+            "Data",
+            "Input",
+            "Load B, A",
+            "Halt",
+        ];
+
+        let mut machine = Datapoint::from_assembler(program, 1.0);
+        let cassette = machine.databus.cassette_mut();
+        cassette.set_timing(TapeTiming::Fast);
+        cassette
+            .load(DeckId::Deck1, vec![2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0])
+            .unwrap();
+        machine.run();
+
+        // The last byte is still there, the first one was overwritten
+        assert_eq!(machine.cpu.alpha_registers[1], 0xef);
+        assert_eq!(machine.diagnostics.len(), 1);
+        match machine.diagnostics[0] {
+            Diagnostic::TapeOverrun {
+                address,
+                time_ns,
+                overrun,
+            } => {
+                assert!((4..=7).contains(&address));
+                assert!(time_ns > 0);
+                assert_eq!(
+                    overrun,
+                    ReadOverrun {
+                        deck: DeckId::Deck1,
+                        index: 10,
+                        data: 0xbe
+                    }
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_fast_boot() {
+        let tap_file = boot_tape(vec!["LoadImm B, 0x42", "Halt"]);
+
+        let mut machine = Datapoint::build(&[], 1.0);
+        machine.load_cassette(tap_file, BootMode::Fast).unwrap();
+        assert_eq!(machine.restart, None);
+        assert_eq!(machine.clock.emulated_time_ns, 0);
+
+        machine.run();
+        assert_eq!(machine.cpu.alpha_registers[1], 0x42);
+    }
+
+    #[test]
+    fn test_fast_boot_blank_tape() {
+        let mut machine = Datapoint::build(&[], 1.0);
+        machine.load_cassette(vec![], BootMode::Fast).unwrap();
+        assert!(machine.cpu.memory.iter().all(|byte| *byte == 0));
+
+        // The deck is left empty when the tape is ejected
+        let cassette = machine.databus.cassette_mut();
+        cassette.eject(DeckId::Deck1).unwrap();
+        assert_eq!(cassette.get_first_sector(), Vec::<u8>::new());
     }
 }
@@ -1,11 +1,113 @@
+use anyhow::{bail, Context, Result};
+
 use crate::DP2200::{
+    assembler::assemble,
+    cassette::write_tap_blocks,
     cpu::Cpu,
+    ctos::decode_symbolic,
     datapoint::Datapoint,
     instruction::{Instruction, InstructionType, FLAG_NAME, REG_NAME},
+    tape::{Record, Tape, HEADER_LEN},
 };
 
+// Data bytes per DATA line in a listing
+const DATA_PER_LINE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Listing {
+    // Every line starts with the address it is loaded at
+    Annotated,
+    // Headers are kept as DATA and addresses go in comments,
+    // so assemble_tape turns the listing back into the tape
+    Rebuild,
+}
+
+// The assembler line for an instruction
+fn instruction_line(inst: &Instruction) -> String {
+    let d = REG_NAME[inst.get_destination() as usize];
+    let s = REG_NAME[inst.get_source() as usize];
+
+    let c = inst.get_destination();
+    let c = if c >= 4 { c - 4 } else { c };
+    let c = FLAG_NAME[c as usize];
+    let op = inst.operand;
+    let addr = inst.address;
+    match inst.instruction_type {
+        InstructionType::Unknown => format!("{:#02x}", inst.opcode),
+        InstructionType::LoadImm => format!("LoadImm {}, {}", d, op.unwrap()),
+        InstructionType::Load => format!("Load {}, {}", d, s),
+        InstructionType::AddImm => format!("AddImm {}", op.unwrap()),
+        InstructionType::Add => format!("Add {}", s),
+        InstructionType::AddImmCarry => {
+            format!("AddImmCarry {}", op.unwrap())
+        }
+        InstructionType::AddCarry => format!("AddCarry {}", s),
+        InstructionType::SubImm => format!("SubImm {}", op.unwrap()),
+        InstructionType::Sub => format!("Sub {}", s),
+        InstructionType::SubImmBorrow => {
+            format!("SubImmBorrow {}", op.unwrap())
+        }
+        InstructionType::SubBorrow => format!("SubBorrow {}", s),
+        InstructionType::AndImm => format!("AndImm {}", op.unwrap()),
+        InstructionType::And => format!("And {}", s),
+        InstructionType::OrImm => format!("OrImm {}", op.unwrap()),
+        InstructionType::Or => format!("Or {}", s),
+        InstructionType::XorImm => format!("XorImm {}", op.unwrap()),
+        InstructionType::Xor => format!("Xor {}", s),
+        InstructionType::CompImm => format!("CompImm {}", op.unwrap()),
+        InstructionType::Comp => format!("Comp {}", s),
+        InstructionType::Jump => format!("Jump {:#04x}", addr.unwrap()),
+        InstructionType::JumpIf => {
+            format!("JumpIf {}, {:#04x}", c, addr.unwrap())
+        }
+        InstructionType::JumpIfNot => {
+            format!("JumpIfNot {}, {:#04x}", c, addr.unwrap())
+        }
+        InstructionType::Call => format!("Call {:#04x}", addr.unwrap()),
+        InstructionType::CallIf => {
+            format!("CallIf {}, {:#04x}", c, addr.unwrap())
+        }
+        InstructionType::CallIfNot => {
+            format!("CallIfNot {}, {:#04x}", c, addr.unwrap())
+        }
+        InstructionType::Return => "Return".to_string(),
+        InstructionType::ReturnIf => format!("ReturnIf {}", c),
+        InstructionType::ReturnIfNot => format!("ReturnIfNot {}", c),
+        InstructionType::ShiftRight => "ShiftRight".to_string(),
+        InstructionType::ShiftLeft => "ShiftLeft".to_string(),
+        InstructionType::Nop => "Nop".to_string(),
+        InstructionType::Halt => "Halt".to_string(),
+        InstructionType::Input => "Input".to_string(),
+        InstructionType::Pop => "Pop".to_string(),
+        InstructionType::Push => "Push".to_string(),
+        InstructionType::EnableIntr => "EnableIntr".to_string(),
+        InstructionType::DisableInts => "DisableInts".to_string(),
+        InstructionType::SelectAlpha => "SelectAlpha".to_string(),
+        InstructionType::SelectBeta => "SelectBeta".to_string(),
+        InstructionType::Adr => "Adr".to_string(),
+        InstructionType::Status => "Status".to_string(),
+        InstructionType::Data => "Data".to_string(),
+        InstructionType::Write => "Write".to_string(),
+        InstructionType::Com1 => "Com1".to_string(),
+        InstructionType::Com2 => "Com2".to_string(),
+        InstructionType::Com3 => "Com3".to_string(),
+        InstructionType::Com4 => "Com4".to_string(),
+        InstructionType::Beep => "Beep".to_string(),
+        InstructionType::Click => "Click".to_string(),
+        InstructionType::Deck1 => "Deck1".to_string(),
+        InstructionType::Deck2 => "Deck2".to_string(),
+        InstructionType::Rbk => "Rbk".to_string(),
+        InstructionType::Wbk => "Wbk".to_string(),
+        InstructionType::Bsp => "Bsp".to_string(),
+        InstructionType::Sf => "Sf".to_string(),
+        InstructionType::Sb => "Sb".to_string(),
+        InstructionType::Rewind => "Rewind".to_string(),
+        InstructionType::Tstop => "Tstop".to_string(),
+    }
+}
+
 pub fn disassemble(memory: &[u8]) -> Vec<(u16, String)> {
-    let mut datapoint = Datapoint::build(memory, 1.0);
+    let datapoint = Datapoint::build(memory, 1.0);
     let len = memory.len() as u16;
     let mut cpu = datapoint.cpu;
     let mut addr_to_line = Vec::new();
@@ -24,90 +126,183 @@ pub fn disassemble(memory: &[u8]) -> Vec<(u16, String)> {
             break;
         }
         inst = tmp_inst.unwrap();
-        let d = REG_NAME[inst.get_destination() as usize];
-        let s = REG_NAME[inst.get_source() as usize];
-
-        let c = inst.get_destination();
-        let c = if c >= 4 { c - 4 } else { c };
-        let c = FLAG_NAME[c as usize];
-        let op = inst.operand;
-        let addr = inst.address;
-        let line = match inst.instruction_type {
-            InstructionType::Unknown => format!("{:#02x}", inst.opcode),
-            InstructionType::LoadImm => format!("LoadImm {}, {}", d, op.unwrap()),
-            InstructionType::Load => format!("Load {}, {}", d, s),
-            InstructionType::AddImm => format!("AddImm {}", op.unwrap()),
-            InstructionType::Add => format!("Add {}", s),
-            InstructionType::AddImmCarry => {
-                format!("AddImmCarry {}", op.unwrap())
+        let line = instruction_line(&inst);
+        addr_to_line.push((program_counter, line));
+    }
+    addr_to_line
+}
+
+// Split code into instructions and data. Only instructions that assemble back to
+// the same bytes are kept, everything else is data.
+// Returns (offset, length, instruction) for each piece.
+fn split_code(code: &[u8]) -> Vec<(usize, usize, Option<String>)> {
+    // One cpu holds all of the code, and is pointed at every place an instruction can start
+    let mut cpu = Cpu::build();
+    let len = code.len().min(cpu.memory.len());
+    cpu.memory[..len].copy_from_slice(&code[..len]);
+
+    let mut pieces = Vec::new();
+    let mut pos = 0;
+    while pos < code.len() {
+        cpu.program_counter = pos as u16;
+        let inst = match cpu.fetch_instruction() {
+            Some(inst) if pos < len && inst.instruction_type != InstructionType::Unknown => {
+                Some(instruction_line(&inst))
+            }
+            _ => None,
+        };
+        match inst.map(|inst| (assemble(vec![&inst]), inst)) {
+            Some((Ok(bytes), inst)) if code[pos..].starts_with(&bytes) => {
+                pieces.push((pos, bytes.len(), Some(inst)));
+                pos += bytes.len();
+            }
+            _ => {
+                pieces.push((pos, 1, None));
+                pos += 1;
             }
-            InstructionType::AddCarry => format!("AddCarry {}", s),
-            InstructionType::SubImm => format!("SubImm {}", op.unwrap()),
-            InstructionType::Sub => format!("Sub {}", s),
-            InstructionType::SubImmBorrow => {
-                format!("SubImmBorror {}", op.unwrap())
+        }
+    }
+    pieces
+}
+
+fn data_line(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:#04x}", b)).collect();
+    format!("DATA {}", bytes.join(","))
+}
+
+fn push_data(out: &mut String, bytes: &[u8]) {
+    for chunk in bytes.chunks(DATA_PER_LINE) {
+        out.push_str(&data_line(chunk));
+        out.push('\n');
+    }
+}
+
+fn push_code(out: &mut String, code: &[u8], address: u16, listing: Listing) {
+    let pieces = split_code(code);
+    let mut i = 0;
+    while i < pieces.len() {
+        let (offset, len, inst) = &pieces[i];
+        // Runs of data are gathered into one line
+        let (line, len) = match inst {
+            Some(inst) => (inst.clone(), *len),
+            None => {
+                let len = pieces[i..]
+                    .iter()
+                    .take(DATA_PER_LINE)
+                    .take_while(|(_, _, inst)| inst.is_none())
+                    .count();
+                (data_line(&code[*offset..offset + len]), len)
+            }
+        };
+        i += if inst.is_some() { 1 } else { len };
+
+        let address = address.wrapping_add(*offset as u16);
+        let line = match listing {
+            Listing::Annotated => format!("{:#06x}: {}\n", address, line),
+            Listing::Rebuild => format!("{} # {:#06x}\n", line, address),
+        };
+        out.push_str(&line);
+    }
+}
+
+// List every record on the tape, with the file it belongs to and where it is loaded
+pub fn disassemble_tape(tape: &Tape, listing: Listing) -> String {
+    let mut out = String::new();
+    for (i, (file, record)) in tape.with_files().enumerate() {
+        let file = file.map_or(String::new(), |no| format!(", file {}", no));
+        let bytes = record.to_bytes();
+        match record {
+            Record::Boot(data) => {
+                out.push_str(&format!(
+                    "\n# Record {}: boot block, {} bytes\n",
+                    i,
+                    data.len()
+                ));
+                push_code(&mut out, data, 0, listing);
             }
-            InstructionType::SubBorrow => format!("SubBorrow {}", s),
-            InstructionType::AndImm => format!("AndImm {}", op.unwrap()),
-            InstructionType::And => format!("And {}", s),
-            InstructionType::OrImm => format!("OrImm {}", op.unwrap()),
-            InstructionType::Or => format!("Or {}", s),
-            InstructionType::XorImm => format!("XorImm {}", op.unwrap()),
-            InstructionType::Xor => format!("Xor {}", s),
-            InstructionType::CompImm => format!("CompImm {}", op.unwrap()),
-            InstructionType::Comp => format!("Comp {}", s),
-            InstructionType::Jump => format!("Jump {:#04x}", addr.unwrap()),
-            InstructionType::JumpIf => {
-                format!("JumpIf {}, {:#04x}", c, addr.unwrap())
+            Record::FileMarker(no) => {
+                out.push_str(&format!("\n# Record {}: file marker {}\n", i, no));
+                if listing == Listing::Rebuild {
+                    push_data(&mut out, &bytes);
+                }
             }
-            InstructionType::JumpIfNot => {
-                format!("JumpIfNot {}, {:#04x}", c, addr.unwrap())
+            Record::Numeric(data) => {
+                if data.payload.is_empty() {
+                    out.push_str(&format!(
+                        "\n# Record {}{}: numeric, end of load, start at {:#06x}\n",
+                        i, file, data.address
+                    ));
+                } else {
+                    out.push_str(&format!(
+                        "\n# Record {}{}: numeric, {} bytes at {:#06x}\n",
+                        i,
+                        file,
+                        data.payload.len(),
+                        data.address
+                    ));
+                }
+                if !data.checks_ok() {
+                    out.push_str("# Check bytes do not match\n");
+                }
+                if listing == Listing::Rebuild {
+                    push_data(&mut out, &bytes[..HEADER_LEN]);
+                }
+                push_code(&mut out, &data.payload, data.address, listing);
             }
-            InstructionType::Call => format!("Call {:#04x}", addr.unwrap()),
-            InstructionType::CallIf => {
-                format!("CallIf {}, {:#04x}", c, addr.unwrap())
+            Record::Symbolic(data) => {
+                out.push_str(&format!(
+                    "\n# Record {}{}: symbolic, {} bytes\n",
+                    i,
+                    file,
+                    data.payload.len()
+                ));
+                for line in decode_symbolic(&data.payload).lines() {
+                    out.push_str(&format!("# | {}\n", line));
+                }
+                if listing == Listing::Rebuild {
+                    push_data(&mut out, &bytes);
+                }
             }
-            InstructionType::CallIfNot => {
-                format!("CallIfNot {}, {:#04x}", c, addr.unwrap())
+            Record::Unknown(data) => {
+                out.push_str(&format!(
+                    "\n# Record {}{}: unknown, {} bytes\n",
+                    i,
+                    file,
+                    data.len()
+                ));
+                push_data(&mut out, data);
             }
-            InstructionType::Return => "Return".to_string(),
-            InstructionType::ReturnIf => format!("ReturnIf {}", c),
-            InstructionType::ReturnIfNot => format!("ReturnIfNot {}", c),
-            InstructionType::ShiftRight => "ShiftRight".to_string(),
-            InstructionType::ShiftLeft => "ShiftLeft".to_string(),
-            InstructionType::Nop => "Nop".to_string(),
-            InstructionType::Halt => "Halt".to_string(),
-            InstructionType::Input => "Input".to_string(),
-            InstructionType::Pop => "Pop".to_string(),
-            InstructionType::Push => "Push".to_string(),
-            InstructionType::EnableIntr => "EnbleIntr".to_string(),
-            InstructionType::DisableInts => "DisableIntr".to_string(),
-            InstructionType::SelectAlpha => "SelecctAlpha".to_string(),
-            InstructionType::SelectBeta => "SelectBeta".to_string(),
-            InstructionType::Adr => "Adr".to_string(),
-            InstructionType::Status => "Status".to_string(),
-            InstructionType::Data => "Data".to_string(),
-            InstructionType::Write => "Write".to_string(),
-            InstructionType::Com1 => "Com1".to_string(),
-            InstructionType::Com2 => "Com2".to_string(),
-            InstructionType::Com3 => "Com3".to_string(),
-            InstructionType::Com4 => "Com4".to_string(),
-            InstructionType::Beep => "Beep".to_string(),
-            InstructionType::Click => "Click".to_string(),
-            InstructionType::Deck1 => "Deck1".to_string(),
-            InstructionType::Deck2 => "Deck2".to_string(),
-            InstructionType::Rbk => "Rbk".to_string(),
-            InstructionType::Wbk => "Wbk".to_string(),
-            InstructionType::Bsp => "Bsp".to_string(),
-            InstructionType::Sf => "Sf".to_string(),
-            InstructionType::Sb => "Sb".to_string(),
-            InstructionType::Rewind => "Rewing".to_string(),
-            InstructionType::Tstop => "Tstop".to_string(),
-        };
+        }
+    }
+    out
+}
 
-        addr_to_line.push((program_counter, line));
+// The record comments of a listing start with this
+const RECORD_COMMENT: &str = "# Record ";
+
+// Turn a Rebuild listing back into a .tap. Every record is assembled on its own and
+// written as a block of its own, so the gaps between the records are where they were.
+pub fn assemble_tape(listing: &str) -> Result<Vec<u8>> {
+    let mut records: Vec<Vec<&str>> = Vec::new();
+    for line in listing.lines() {
+        if line.starts_with(RECORD_COMMENT) {
+            records.push(Vec::new());
+        }
+        match records.last_mut() {
+            Some(record) => record.push(line),
+            None if assemble(vec![line])?.is_empty() => {}
+            None => bail!("{} is not part of a record", line),
+        }
     }
-    addr_to_line
+
+    let blocks = records
+        .into_iter()
+        .map(|lines| {
+            let header = lines[0];
+            assemble(lines).with_context(|| format!("Could not assemble {}", header))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(write_tap_blocks(&blocks))
 }
 
 #[cfg(test)]
@@ -131,4 +326,61 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_mnemonics_assemble() {
+        // Every instruction the disassembler names is one the assembler knows,
+        // and it assembles to an encoding of the same instruction
+        for opcode in 0..=255u8 {
+            let mut cpu = Cpu::build();
+            cpu.memory[..3].copy_from_slice(&[opcode, 0x12, 0x03]);
+            let inst = cpu.fetch_instruction().unwrap();
+            if inst.instruction_type == InstructionType::Unknown {
+                continue;
+            }
+            let line = instruction_line(&inst);
+            let bytes = assemble(vec![&line]).unwrap();
+            assert_eq!(disassemble(&bytes)[0].1, line, "opcode {:#04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_split_code() {
+        // 0x01 is also a halt, but the assembler writes it as 0x00,
+        // and the jump only has one byte of its address before the end of the code
+        let code = [0x06, 10, 0x01, 0x00, 0x44, 0x00];
+        assert_eq!(
+            split_code(&code),
+            vec![
+                (0, 2, Some("LoadImm A, 10".to_string())),
+                (2, 1, None),
+                (3, 1, Some("Halt".to_string())),
+                (4, 1, None),
+                (5, 1, Some("Halt".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rebuild_tape() {
+        let tap_file = include_bytes!("../../tstdis1.1_3-75.fixed.tap");
+        let tape = Tape::parse(tap_file, true).unwrap();
+        let listing = format!(
+            "# Disassembly of tstdis1.1_3-75.fixed.tap\n{}",
+            disassemble_tape(&tape, Listing::Rebuild)
+        );
+
+        assert_eq!(assemble_tape(&listing).unwrap(), tap_file.to_vec());
+        assert!(assemble_tape(&format!("Halt\n{}", listing)).is_err());
+    }
+
+    #[test]
+    fn test_annotated_tape() {
+        let tape = Tape::parse(include_bytes!("../../tstdis1.1_3-75.fixed.tap"), true).unwrap();
+        let listing = disassemble_tape(&tape, Listing::Annotated);
+
+        assert!(listing.contains("# Record 0: boot block, 512 bytes\n0x0000: "));
+        assert!(listing.contains("# Record 1: file marker 0\n"));
+        assert!(listing.contains("# Record 14: file marker 32\n"));
+    }
 }
@@ -0,0 +1,246 @@
+// Bad media, for testing how programs cope with tapes that do not read back cleanly.
+// Every fault is decided from the seed and the position on the tape, so a damaged spot
+// reads the same way every time it passes the head, and the same seed gives the same tape.
+// The tape itself is never changed, the faults are only applied to what the head reads.
+
+use std::ops::Range;
+
+use super::cassette::CassetteData;
+
+// Salts, so the decisions for one position do not depend on each other
+const DROPOUT: u64 = 1;
+const BIT_FLIP: u64 = 2;
+const GAP: u64 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TapeFaults {
+    pub seed: u64,
+    // Chance that a byte is not read at all
+    pub dropout: f64,
+    // Chance that a byte is read with one bit flipped
+    pub bit_flip: f64,
+    // Chance that the head does not notice a gap, so the blocks on both sides run together
+    pub missing_gap: f64,
+    // Chance that only the second half of a gap is noticed
+    pub short_gap: f64,
+    // How much longer every byte takes to pass the head, 1.0 is a tape in good shape
+    pub stretch: f64,
+}
+
+// splitmix64, good enough to spread the seed and position over the whole range
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+// The run of gap that index is in
+fn gap_run(data: &[CassetteData], index: usize) -> Range<usize> {
+    let is_gap = |i: &usize| data[*i] == CassetteData::Gap;
+    let start = (0..index).rev().take_while(is_gap).last().unwrap_or(index);
+    let end = (index..data.len())
+        .take_while(is_gap)
+        .last()
+        .unwrap_or(index);
+    start..end + 1
+}
+
+impl TapeFaults {
+    // A tape without faults, to build on
+    pub fn new(seed: u64) -> TapeFaults {
+        TapeFaults {
+            seed,
+            dropout: 0.0,
+            bit_flip: 0.0,
+            missing_gap: 0.0,
+            short_gap: 0.0,
+            stretch: 1.0,
+        }
+    }
+
+    fn hash(&self, index: usize, salt: u64) -> u64 {
+        mix(mix(self.seed ^ salt) ^ index as u64)
+    }
+
+    // A number in 0.0..1.0 for a position on the tape
+    fn roll(&self, index: usize, salt: u64) -> f64 {
+        (self.hash(index, salt) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // What the head reads at index, None when nothing is picked up. gap holds the run of gap
+    // read last, so a long gap is only searched once. The caller keeps it between reads,
+    // and sets it to None when the tape is written to.
+    pub fn read(
+        &self,
+        data: &[CassetteData],
+        index: usize,
+        gap: &mut Option<Range<usize>>,
+    ) -> Option<CassetteData> {
+        match *data.get(index)? {
+            CassetteData::Data(_) if self.roll(index, DROPOUT) < self.dropout => None,
+            CassetteData::Data(byte) if self.roll(index, BIT_FLIP) < self.bit_flip => {
+                let bit = self.hash(index, BIT_FLIP) % 8;
+                Some(CassetteData::Data(byte ^ 1 << bit))
+            }
+            CassetteData::Data(byte) => Some(CassetteData::Data(byte)),
+            CassetteData::Gap => {
+                let run = match gap {
+                    Some(run) if run.contains(&index) => run.clone(),
+                    _ => gap.insert(gap_run(data, index)).clone(),
+                };
+                // The whole gap is decided at once, by where it starts
+                let roll = self.roll(run.start, GAP);
+                // A missing gap loses all of it, a short gap the first half
+                let missing = roll < self.missing_gap;
+                let short =
+                    roll < self.missing_gap + self.short_gap && index - run.start < run.len() / 2;
+                if missing || short {
+                    None
+                } else {
+                    Some(CassetteData::Gap)
+                }
+            }
+        }
+    }
+
+    // Databus clocks for a byte on this tape
+    pub fn byte_clocks(&self, clocks: usize) -> usize {
+        ((clocks as f64 * self.stretch).round() as usize).max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tape() -> Vec<CassetteData> {
+        let mut data = [CassetteData::Gap].repeat(10);
+        for _ in 0..100 {
+            data.extend((0..100).map(CassetteData::Data));
+            data.extend([CassetteData::Gap].repeat(10));
+        }
+        data
+    }
+
+    #[test]
+    fn test_no_faults() {
+        let data = tape();
+        let faults = TapeFaults::new(1);
+        for i in 0..data.len() {
+            assert_eq!(faults.read(&data, i, &mut None), Some(data[i]));
+        }
+        assert_eq!(faults.byte_clocks(431), 431);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let data = tape();
+        let faults = TapeFaults {
+            dropout: 0.1,
+            bit_flip: 0.1,
+            short_gap: 0.3,
+            missing_gap: 0.3,
+            ..TapeFaults::new(42)
+        };
+        let read = |faults: &TapeFaults| -> Vec<_> {
+            let mut gap = None;
+            (0..data.len())
+                .map(|i| faults.read(&data, i, &mut gap))
+                .collect()
+        };
+
+        assert_eq!(read(&faults), read(&faults.clone()));
+        let other = TapeFaults {
+            seed: 43,
+            ..faults.clone()
+        };
+        assert_ne!(read(&faults), read(&other));
+    }
+
+    #[test]
+    fn test_byte_faults() {
+        let data = tape();
+        let faults = TapeFaults {
+            dropout: 0.05,
+            bit_flip: 0.1,
+            ..TapeFaults::new(7)
+        };
+        let (mut dropped, mut flipped, mut bytes) = (0, 0, 0);
+        for (i, cell) in data.iter().enumerate() {
+            match (cell, faults.read(&data, i, &mut None)) {
+                (CassetteData::Data(_), None) => dropped += 1,
+                (CassetteData::Data(a), Some(CassetteData::Data(b))) => {
+                    assert!(a == &b || (a ^ b).count_ones() == 1);
+                    if a != &b {
+                        flipped += 1;
+                    }
+                }
+                (CassetteData::Gap, read) => assert_eq!(read, Some(CassetteData::Gap)),
+                (_, read) => panic!("Read {:?} from {:?}", read, cell),
+            }
+            bytes += matches!(cell, CassetteData::Data(_)) as usize;
+        }
+
+        // Roughly the chances asked for, out of 10000 bytes
+        assert!((350..650).contains(&dropped), "{}", dropped);
+        assert!((700..1200).contains(&flipped), "{}", flipped);
+        assert_eq!(bytes, 10000);
+    }
+
+    #[test]
+    fn test_gap_faults() {
+        let data = tape();
+        let missing = TapeFaults {
+            missing_gap: 1.0,
+            ..TapeFaults::new(3)
+        };
+        assert!(
+            (0..data.len()).all(|i| missing.read(&data, i, &mut None) != Some(CassetteData::Gap))
+        );
+
+        let short = TapeFaults {
+            short_gap: 1.0,
+            ..TapeFaults::new(3)
+        };
+        let gap: Vec<_> = (100..120)
+            .map(|i| short.read(&data, i, &mut None))
+            .collect();
+        assert_eq!(gap[10..15], [None; 5]);
+        assert_eq!(gap[15..20], [Some(CassetteData::Gap); 5]);
+
+        // The gap that was read last is kept, and reads the same as a fresh search
+        let mut kept = None;
+        let again: Vec<_> = (100..120)
+            .map(|i| short.read(&data, i, &mut kept))
+            .collect();
+        assert_eq!(again, gap);
+        assert_eq!(kept, Some(110..120));
+    }
+
+    #[test]
+    fn test_long_gap() {
+        // Every byte of a long gap has to be read without searching the whole gap again
+        let mut data = [CassetteData::Gap].repeat(1 << 20);
+        data.push(CassetteData::Data(0x42));
+        let faults = TapeFaults {
+            short_gap: 1.0,
+            ..TapeFaults::new(5)
+        };
+        let mut gap = None;
+        let read: Vec<_> = (0..data.len())
+            .map(|i| faults.read(&data, i, &mut gap))
+            .collect();
+        assert_eq!(read.iter().filter(|r| r.is_none()).count(), 1 << 19);
+        assert_eq!(read[1 << 20], Some(CassetteData::Data(0x42)));
+    }
+
+    #[test]
+    fn test_stretch() {
+        let faults = TapeFaults {
+            stretch: 1.1,
+            ..TapeFaults::new(0)
+        };
+        assert_eq!(faults.byte_clocks(431), 474);
+    }
+}
@@ -1,5 +1,9 @@
+use std::any::Any;
+
 use log::info;
 
+use super::peripheral::Peripheral;
+
 #[derive(Debug, Clone)]
 pub struct Keyboard {
     display_pressed: bool,
@@ -10,21 +14,75 @@ pub struct Keyboard {
 
 pub const KEYBOARD_ADDR: u8 = 0o341;
 
-fn convert_key(key: String) -> Option<u8> {
-    if key.len() == 1 {
-        let key: Vec<char> = key.chars().collect();
-        if key[0].is_ascii() {
-            return Some(key[0] as u8);
+// The keys of the 2200 keyboard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DpKey {
+    // A key on the main keyboard, with the code it sends
+    Char(u8),
+    // A digit on the numeric pad, it sends the same code as the digit on the main keyboard
+    Pad(PadDigit),
+    Enter,
+    Backspace,
+    Cancel,
+    Delete,
+    // The status keys send no code, they are only seen in the status while held down
+    Keyboard,
+    Display,
+}
+
+// A key on the numeric pad. There are only the digits 0 to 9, so it can only be made
+// through new, which checks the digit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PadDigit(u8);
+
+impl PadDigit {
+    pub fn new(digit: u8) -> Option<PadDigit> {
+        (digit <= 9).then_some(PadDigit(digit))
+    }
+
+    pub fn digit(&self) -> u8 {
+        self.0
+    }
+}
+
+impl DpKey {
+    // The code the key puts in the buffer
+    pub fn code(&self) -> Option<u8> {
+        match self {
+            DpKey::Char(code) => Some(*code),
+            DpKey::Pad(digit) => Some(b'0' + digit.digit()),
+            DpKey::Enter => Some(13),
+            DpKey::Cancel => Some(24),
+            DpKey::Backspace => Some(8),
+            DpKey::Delete => Some(127),
+            DpKey::Keyboard | DpKey::Display => None,
         }
     }
 
-    return match key.as_str() {
-        "Enter" => Some(13),
-        "Cancel" => Some(24),
-        "Backspace" => Some(8),
-        "Delete" => Some(127),
-        _ => None,
-    };
+    // A key by the name used in keymaps: "enter", "pad7", "display", a single
+    // character like "A", or the code of a character like "0x1b"
+    pub fn from_name(name: &str) -> Option<DpKey> {
+        let key = match name.to_ascii_lowercase().as_str() {
+            "enter" => DpKey::Enter,
+            "backspace" => DpKey::Backspace,
+            "cancel" => DpKey::Cancel,
+            "delete" => DpKey::Delete,
+            "keyboard" => DpKey::Keyboard,
+            "display" => DpKey::Display,
+            "space" => DpKey::Char(b' '),
+            lower => {
+                if let Some(digit) = lower.strip_prefix("pad") {
+                    return digit.parse().ok().and_then(PadDigit::new).map(DpKey::Pad);
+                }
+                let mut chars = name.chars();
+                return match (chars.next(), chars.next()) {
+                    (Some(c), None) if c.is_ascii() => Some(DpKey::Char(c as u8)),
+                    _ => parse_int::parse::<u8>(name).ok().map(DpKey::Char),
+                };
+            }
+        };
+        Some(key)
+    }
 }
 
 impl Keyboard {
@@ -37,66 +95,77 @@ impl Keyboard {
         }
     }
 
-    pub fn keydown(&mut self, key: String) {
-        if let Some(key_code) = convert_key(key.clone()) {
+    pub fn keydown(&mut self, key: DpKey) {
+        if let Some(key_code) = key.code() {
             self.key_buf = key_code;
             self.key_ready = true;
-            info!("Got key: {}", key);
+            info!("Got key: {:?}", key);
         }
 
-        if key == "Tab" {
-            self.display_pressed = true;
-        }
-
-        if key == "Keyboard" {
-            self.keyboard_pressed = true;
+        match key {
+            DpKey::Display => self.display_pressed = true,
+            DpKey::Keyboard => self.keyboard_pressed = true,
+            _ => {}
         }
     }
 
-    pub fn keyup(&mut self, key: String) {
-        if let Some(key_code) = convert_key(key.clone()) {
+    pub fn keyup(&mut self, key: DpKey) {
+        if let Some(key_code) = key.code() {
             if key_code == self.key_buf {
                 self.key_ready = false;
                 info!("Key release");
             }
         }
 
-        if key == "Tab" {
-            self.display_pressed = false;
+        match key {
+            DpKey::Display => self.display_pressed = false,
+            DpKey::Keyboard => self.keyboard_pressed = false,
+            _ => {}
         }
+    }
+}
 
-        if key == "Keyboard" {
-            self.keyboard_pressed = false;
-        }
+impl Peripheral for Keyboard {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
     }
 
-    pub fn get_status(&self) -> u8 {
+    fn get_status(&mut self) -> u8 {
         let mut status :u8 = 0;
         if self.key_ready {
-            status |= (1<<1);
+            status |= 1<<1;
         }
 
         if self.keyboard_pressed {
-            status |= (1<<2);
+            status |= 1<<2;
         }
 
         if self.display_pressed {
-            status |= (1<<3);
+            status |= 1<<3;
         }
     
         status
     }
 
-    pub fn strobe(&mut self) {
+    fn strobe(&mut self) {
         self.key_ready = false;
     }
 
-    pub fn clock(&self) {}
-
-    pub fn get_data(&mut self) -> u8 {
+    fn get_data(&mut self) -> u8 {
         self.key_buf
     }
 
-    pub fn write_data(&mut self, data: u8) {}
+    fn reset(&mut self) {
+        self.key_ready = false;
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
 }
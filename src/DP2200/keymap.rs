@@ -0,0 +1,205 @@
+// Which key on the host keyboard presses which key on the 2200.
+//
+// Host keys are named the way the terminal reports them: "a", "Enter", "Tab", "F1",
+// "Page Up". A keymap file has one binding per line, the host key and the 2200 key
+// on either side of the first equals sign. A line that starts with two of them binds
+// the equals key itself:
+//
+//   # Host key = 2200 key
+//   F1 = keyboard
+//   Tab=display
+//   F3 = cancel
+//   Page Up = pad9
+//   = = delete
+//
+// The file only has to list what is different from the default keymap.
+// Esc and F2 are taken by the emulator, and binding them is an error.
+
+use std::{collections::BTreeMap, fmt};
+
+use super::keyboard::DpKey;
+
+// Esc quits and F2 changes the tape in deck 2, the keyboard never sees them
+pub const RESERVED_KEYS: [&str; 2] = ["Esc", "F2"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeymapError {
+    // The line is not "host = key"
+    MissingEquals { line: usize },
+    UnknownKey { line: usize, name: String },
+    ReservedKey { line: usize, host: String },
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeymapError::MissingEquals { line } => {
+                write!(f, "Line {}: expected <host key> = <2200 key>", line)
+            }
+            KeymapError::UnknownKey { line, name } => {
+                write!(f, "Line {}: {} is not a key on the 2200", line, name)
+            }
+            KeymapError::ReservedKey { line, host } => {
+                write!(f, "Line {}: {} is used by the emulator", line, host)
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeymapError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    bindings: BTreeMap<String, DpKey>,
+}
+
+impl Default for Keymap {
+    // Every printable character types itself, the rest is bound here.
+    // The RESERVED_KEYS are left alone.
+    fn default() -> Self {
+        let bindings = [
+            ("Enter", DpKey::Enter),
+            ("Backspace", DpKey::Backspace),
+            ("Del", DpKey::Delete),
+            ("Space", DpKey::Char(b' ')),
+            ("Tab", DpKey::Display),
+            ("F1", DpKey::Keyboard),
+            ("F3", DpKey::Cancel),
+        ];
+        Keymap {
+            bindings: bindings
+                .iter()
+                .map(|(host, key)| (host.to_string(), *key))
+                .collect(),
+        }
+    }
+}
+
+impl Keymap {
+    // The default keymap, with the bindings from a keymap file on top
+    pub fn parse(text: &str) -> Result<Keymap, KeymapError> {
+        let mut keymap = Keymap::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let equals_key = line
+                .strip_prefix('=')
+                .and_then(|rest| rest.trim_start().strip_prefix('='));
+            let (host, name) = match equals_key {
+                Some(name) => ("=", name),
+                None => line
+                    .split_once('=')
+                    .ok_or(KeymapError::MissingEquals { line: i + 1 })?,
+            };
+            let host = host.trim();
+            if host.is_empty() {
+                return Err(KeymapError::MissingEquals { line: i + 1 });
+            }
+            if RESERVED_KEYS.contains(&host) {
+                return Err(KeymapError::ReservedKey {
+                    line: i + 1,
+                    host: host.to_string(),
+                });
+            }
+            let name = name.trim();
+            let key = DpKey::from_name(name).ok_or_else(|| KeymapError::UnknownKey {
+                line: i + 1,
+                name: name.to_string(),
+            })?;
+            keymap.bind(host, key);
+        }
+        Ok(keymap)
+    }
+
+    pub fn bind(&mut self, host: &str, key: DpKey) {
+        self.bindings.insert(host.to_string(), key);
+    }
+
+    // The 2200 key for a host key, by the name the terminal gives it
+    pub fn get(&self, host: &str) -> Option<DpKey> {
+        if let Some(key) = self.bindings.get(host) {
+            return Some(*key);
+        }
+        let mut chars = host.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_graphic() => Some(DpKey::Char(c as u8)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DP2200::keyboard::PadDigit;
+
+    #[test]
+    fn test_default() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.get("a"), Some(DpKey::Char(b'a')));
+        assert_eq!(keymap.get("="), Some(DpKey::Char(b'=')));
+        assert_eq!(keymap.get("Space"), Some(DpKey::Char(b' ')));
+        assert_eq!(keymap.get("Enter"), Some(DpKey::Enter));
+        assert_eq!(keymap.get("Tab"), Some(DpKey::Display));
+        assert_eq!(keymap.get("F1"), Some(DpKey::Keyboard));
+        assert_eq!(keymap.get("Esc"), None);
+        assert_eq!(keymap.get("ø"), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        let keymap = Keymap::parse(
+            "# Keep Tab for the terminal\n\
+             Tab = a\n\
+             \n\
+             Page Up = pad9\n\
+             = = cancel\n\
+             F5=0x1b\n\
+             F6  =  display\n\
+             F7 = =\n",
+        )
+        .unwrap();
+        let pad9 = DpKey::Pad(PadDigit::new(9).unwrap());
+        assert_eq!(keymap.get("Tab"), Some(DpKey::Char(b'a')));
+        assert_eq!(keymap.get("Page Up"), Some(pad9));
+        assert_eq!(pad9.code(), Some(b'9'));
+        assert_eq!(PadDigit::new(10), None);
+        assert_eq!(keymap.get("="), Some(DpKey::Cancel));
+        assert_eq!(keymap.get("F5"), Some(DpKey::Char(0x1b)));
+        assert_eq!(keymap.get("F6"), Some(DpKey::Display));
+        assert_eq!(keymap.get("F7"), Some(DpKey::Char(b'=')));
+
+        let keymap = Keymap::parse("==delete").unwrap();
+        assert_eq!(keymap.get("="), Some(DpKey::Delete));
+        // Everything else is still the default
+        assert_eq!(keymap.get("Enter"), Some(DpKey::Enter));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            Keymap::parse("F1 = keyboard\nF2 keyboard"),
+            Err(KeymapError::MissingEquals { line: 2 })
+        );
+        assert_eq!(
+            Keymap::parse("= keyboard"),
+            Err(KeymapError::MissingEquals { line: 1 })
+        );
+        assert_eq!(
+            Keymap::parse("F1 = pad10"),
+            Err(KeymapError::UnknownKey {
+                line: 1,
+                name: "pad10".to_string()
+            })
+        );
+        assert_eq!(
+            Keymap::parse("# Change tapes with F3\nF3 = cancel\nF2 = cancel"),
+            Err(KeymapError::ReservedKey {
+                line: 3,
+                host: "F2".to_string()
+            })
+        );
+    }
+}
@@ -1,10 +1,19 @@
+pub mod asciicast;
 pub mod assembler;
 pub mod cassette;
+pub mod charset;
 pub mod clock;
 pub mod cpu;
+pub mod ctos;
 pub mod databus;
 pub mod datapoint;
 pub mod disassembler;
+pub mod faults;
 pub mod instruction;
 pub mod keyboard;
+pub mod keymap;
+pub mod peripheral;
+pub mod render;
 pub mod screen;
+pub mod tape;
+pub mod tape_image;
@@ -0,0 +1,39 @@
+use std::{any::Any, fmt::Debug};
+
+// A device that can be attached to the databus.
+// The databus only talks to the peripherals attached at the selected address,
+// and ORs their outputs together, like the wired-or of the real bus.
+// Every method except the downcasting helpers has a default implementation,
+// so a device only has to implement the parts of the protocol it uses.
+pub trait Peripheral: Any + Debug {
+    fn as_any(&self) -> &dyn Any;
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    fn get_status(&mut self) -> u8 {
+        0
+    }
+
+    fn get_data(&mut self) -> u8 {
+        0
+    }
+
+    fn write_data(&mut self, _data: u8) {}
+
+    // Called when the cpu executes an `Input` while the bus is in data mode
+    fn strobe(&mut self) {}
+
+    fn com1(&mut self, _data: u8) {}
+
+    fn com2(&mut self, _data: u8) {}
+
+    fn com3(&mut self, _data: u8) {}
+
+    fn com4(&mut self, _data: u8) {}
+
+    // Called on every databus clock (153.6kHz), whether the device is selected or not
+    fn clock(&mut self) {}
+
+    // Return the device to its power on state. Media, like a loaded tape, is kept.
+    fn reset(&mut self) {}
+}
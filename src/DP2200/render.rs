@@ -0,0 +1,462 @@
+// Draws the screen as an image, without a window system, for documentation and tests.
+//
+// The CRT shows 12 lines of 80 characters, each a 5x7 dot matrix. Every character gets a
+// cell of CELL_WIDTH x CELL_HEIGHT dots, with the glyph one dot in from the top left,
+// and the cursor as a line under the glyph.
+
+use std::{fmt::Write, path::Path};
+
+use super::screen::Screen;
+
+pub const CELL_WIDTH: usize = 7;
+pub const CELL_HEIGHT: usize = 10;
+pub const WIDTH: usize = 80 * CELL_WIDTH;
+pub const HEIGHT: usize = 12 * CELL_HEIGHT;
+const CURSOR_ROW: usize = 9;
+
+// The glyphs for 0x20 to 0x5f, the codes the character generator can show.
+// Each glyph is 5 columns from left to right, bit 0 is the top row.
+const FONT: [[u8; 5]; 64] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], // space
+    [0x00, 0x00, 0x5f, 0x00, 0x00], // !
+    [0x00, 0x07, 0x00, 0x07, 0x00], // "
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], // #
+    [0x24, 0x2a, 0x7f, 0x2a, 0x12], // $
+    [0x23, 0x13, 0x08, 0x64, 0x62], // %
+    [0x36, 0x49, 0x56, 0x20, 0x50], // &
+    [0x00, 0x05, 0x03, 0x00, 0x00], // '
+    [0x00, 0x1c, 0x22, 0x41, 0x00], // (
+    [0x00, 0x41, 0x22, 0x1c, 0x00], // )
+    [0x14, 0x08, 0x3e, 0x08, 0x14], // *
+    [0x08, 0x08, 0x3e, 0x08, 0x08], // +
+    [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+    [0x08, 0x08, 0x08, 0x08, 0x08], // -
+    [0x00, 0x60, 0x60, 0x00, 0x00], // .
+    [0x20, 0x10, 0x08, 0x04, 0x02], // /
+    [0x3e, 0x51, 0x49, 0x45, 0x3e], // 0
+    [0x00, 0x42, 0x7f, 0x40, 0x00], // 1
+    [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+    [0x21, 0x41, 0x45, 0x4b, 0x31], // 3
+    [0x18, 0x14, 0x12, 0x7f, 0x10], // 4
+    [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+    [0x3c, 0x4a, 0x49, 0x49, 0x30], // 6
+    [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+    [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+    [0x06, 0x49, 0x49, 0x29, 0x1e], // 9
+    [0x00, 0x36, 0x36, 0x00, 0x00], // :
+    [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+    [0x08, 0x14, 0x22, 0x41, 0x00], // <
+    [0x14, 0x14, 0x14, 0x14, 0x14], // =
+    [0x00, 0x41, 0x22, 0x14, 0x08], // >
+    [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+    [0x32, 0x49, 0x79, 0x41, 0x3e], // @
+    [0x7e, 0x11, 0x11, 0x11, 0x7e], // A
+    [0x7f, 0x49, 0x49, 0x49, 0x36], // B
+    [0x3e, 0x41, 0x41, 0x41, 0x22], // C
+    [0x7f, 0x41, 0x41, 0x22, 0x1c], // D
+    [0x7f, 0x49, 0x49, 0x49, 0x41], // E
+    [0x7f, 0x09, 0x09, 0x09, 0x01], // F
+    [0x3e, 0x41, 0x49, 0x49, 0x7a], // G
+    [0x7f, 0x08, 0x08, 0x08, 0x7f], // H
+    [0x00, 0x41, 0x7f, 0x41, 0x00], // I
+    [0x20, 0x40, 0x41, 0x3f, 0x01], // J
+    [0x7f, 0x08, 0x14, 0x22, 0x41], // K
+    [0x7f, 0x40, 0x40, 0x40, 0x40], // L
+    [0x7f, 0x02, 0x0c, 0x02, 0x7f], // M
+    [0x7f, 0x04, 0x08, 0x10, 0x7f], // N
+    [0x3e, 0x41, 0x41, 0x41, 0x3e], // O
+    [0x7f, 0x09, 0x09, 0x09, 0x06], // P
+    [0x3e, 0x41, 0x51, 0x21, 0x5e], // Q
+    [0x7f, 0x09, 0x19, 0x29, 0x46], // R
+    [0x46, 0x49, 0x49, 0x49, 0x31], // S
+    [0x01, 0x01, 0x7f, 0x01, 0x01], // T
+    [0x3f, 0x40, 0x40, 0x40, 0x3f], // U
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], // V
+    [0x3f, 0x40, 0x38, 0x40, 0x3f], // W
+    [0x63, 0x14, 0x08, 0x14, 0x63], // X
+    [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+    [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+    [0x00, 0x7f, 0x41, 0x41, 0x00], // [
+    [0x02, 0x04, 0x08, 0x10, 0x20], // \
+    [0x00, 0x41, 0x41, 0x7f, 0x00], // ]
+    [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+    [0x40, 0x40, 0x40, 0x40, 0x40], // _
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Palette {
+    // Green dots on a dark screen, like the real CRT
+    GreenPhosphor,
+    // Black on white, for printing
+    Monochrome,
+}
+
+impl Palette {
+    // Background and foreground
+    pub fn colors(&self) -> ([u8; 3], [u8; 3]) {
+        match self {
+            Palette::GreenPhosphor => ([0x0a, 0x14, 0x0a], [0x33, 0xff, 0x66]),
+            Palette::Monochrome => ([0xff, 0xff, 0xff], [0x00, 0x00, 0x00]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Ppm,
+    Png,
+    Svg,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &Path) -> Option<ImageFormat> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "ppm" => Some(ImageFormat::Ppm),
+            "png" => Some(ImageFormat::Png),
+            "svg" => Some(ImageFormat::Svg),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderOptions {
+    pub palette: Palette,
+    // Draw the cursor, when the screen has it turned on
+    pub cursor: bool,
+    // Pixels per dot
+    pub scale: usize,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            palette: Palette::GreenPhosphor,
+            cursor: true,
+            scale: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    // Row by row from the top left
+    pub pixels: Vec<[u8; 3]>,
+}
+
+// Every dot of the screen, WIDTH x HEIGHT, row by row
+pub fn dots(screen: &Screen, cursor: bool) -> Vec<bool> {
+    let mut dots = vec![false; WIDTH * HEIGHT];
+    for line in 0..12 {
+        for character in 0..80 {
+            let glyph = &FONT[screen.get_char(line, character) as usize - ' ' as usize];
+            let x = character * CELL_WIDTH + 1;
+            let y = line * CELL_HEIGHT + 1;
+            for (col, bits) in glyph.iter().enumerate() {
+                for row in 0..7 {
+                    dots[(y + row) * WIDTH + x + col] = bits & (1 << row) != 0;
+                }
+            }
+        }
+    }
+
+    if cursor && screen.cursor_visible() {
+        let (line, character) = screen.cursor_position();
+        let start = (line * CELL_HEIGHT + CURSOR_ROW) * WIDTH + character * CELL_WIDTH + 1;
+        dots[start..start + 5].fill(true);
+    }
+    dots
+}
+
+pub fn rasterize(screen: &Screen, options: &RenderOptions) -> Image {
+    let (background, foreground) = options.palette.colors();
+    let scale = options.scale.max(1);
+    let dots = dots(screen, options.cursor);
+
+    let mut pixels = Vec::with_capacity(WIDTH * HEIGHT * scale * scale);
+    for row in dots.chunks(WIDTH) {
+        let line: Vec<_> = row
+            .iter()
+            .flat_map(|dot| [if *dot { foreground } else { background }].repeat(scale))
+            .collect();
+        for _ in 0..scale {
+            pixels.extend_from_slice(&line);
+        }
+    }
+    Image {
+        width: WIDTH * scale,
+        height: HEIGHT * scale,
+        pixels,
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn push_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+impl Image {
+    // Binary PPM, P6
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        ppm.extend(self.pixels.iter().flatten());
+        ppm
+    }
+
+    // 8 bit RGB. The image data is stored without compression, which keeps the encoder
+    // small, and any PNG reader still takes it.
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(self.height * (1 + self.width * 3));
+        for row in self.pixels.chunks(self.width) {
+            // Filter type none
+            raw.push(0);
+            raw.extend(row.iter().flatten());
+        }
+
+        // A zlib stream of stored deflate blocks
+        let mut zlib = vec![0x78, 0x01];
+        let mut blocks = raw.chunks(0xffff).peekable();
+        if blocks.peek().is_none() {
+            zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        while let Some(block) = blocks.next() {
+            zlib.push(blocks.peek().is_none() as u8);
+            zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+            zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // 8 bits per sample, RGB, no interlace
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        push_chunk(&mut png, b"IHDR", &header);
+        push_chunk(&mut png, b"IDAT", &zlib);
+        push_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn hex(color: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+// One rectangle for every run of dots on a row
+pub fn to_svg(screen: &Screen, options: &RenderOptions) -> String {
+    let (background, foreground) = options.palette.colors();
+    let scale = options.scale.max(1);
+    let dots = dots(screen, options.cursor);
+
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}" shape-rendering="crispEdges">"#,
+        WIDTH * scale,
+        HEIGHT * scale,
+        WIDTH,
+        HEIGHT
+    )
+    .unwrap();
+    writeln!(
+        svg,
+        r#"<rect width="{}" height="{}" fill="{}"/>"#,
+        WIDTH,
+        HEIGHT,
+        hex(background)
+    )
+    .unwrap();
+    writeln!(svg, r#"<g fill="{}">"#, hex(foreground)).unwrap();
+    for (y, row) in dots.chunks(WIDTH).enumerate() {
+        let mut x = 0;
+        while x < WIDTH {
+            if !row[x] {
+                x += 1;
+                continue;
+            }
+            let len = row[x..].iter().take_while(|dot| **dot).count();
+            writeln!(
+                svg,
+                r#"<rect x="{}" y="{}" width="{}" height="1"/>"#,
+                x, y, len
+            )
+            .unwrap();
+            x += len;
+        }
+    }
+    svg.push_str("</g>\n</svg>\n");
+    svg
+}
+
+pub fn render(screen: &Screen, options: &RenderOptions, format: ImageFormat) -> Vec<u8> {
+    match format {
+        ImageFormat::Ppm => rasterize(screen, options).to_ppm(),
+        ImageFormat::Png => rasterize(screen, options).to_png(),
+        ImageFormat::Svg => to_svg(screen, options).into_bytes(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DP2200::peripheral::Peripheral;
+
+    fn screen(text: &str) -> Screen {
+        let mut screen = Screen::new();
+        for (i, byte) in text.bytes().enumerate() {
+            screen.buffer[0][i] = byte;
+        }
+        screen
+    }
+
+    // The dots of one cell as text, for comparing glyphs
+    fn cell(dots: &[bool], line: usize, character: usize) -> Vec<String> {
+        (0..CELL_HEIGHT)
+            .map(|row| {
+                let start = (line * CELL_HEIGHT + row) * WIDTH + character * CELL_WIDTH;
+                dots[start..start + CELL_WIDTH]
+                    .iter()
+                    .map(|dot| if *dot { '#' } else { '.' })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_glyphs() {
+        let dots = dots(&screen("Ha"), true);
+        let h = [
+            ".......", ".#...#.", ".#...#.", ".#...#.", ".#####.", ".#...#.", ".#...#.", ".#...#.",
+            ".......", ".......",
+        ];
+        assert_eq!(cell(&dots, 0, 0), h);
+        // Through the character generator, so lowercase shows as uppercase
+        let a = cell(&dots, 0, 1);
+        assert_eq!(a[1], "..###..");
+        assert_eq!(a[5], ".#####.");
+        assert!(cell(&dots, 0, 2).iter().all(|row| row == "......."));
+    }
+
+    #[test]
+    fn test_cursor() {
+        let mut screen = screen("");
+        screen.set_vertical(3);
+        screen.set_horizontal(10);
+        assert!(dots(&screen, true).iter().all(|dot| !dot));
+
+        screen.com1(1 << 4);
+        let with_cursor = dots(&screen, true);
+        assert_eq!(with_cursor.iter().filter(|dot| **dot).count(), 5);
+        assert_eq!(cell(&with_cursor, 3, 10)[CURSOR_ROW], ".#####.");
+        assert!(dots(&screen, false).iter().all(|dot| !dot));
+    }
+
+    #[test]
+    fn test_ppm() {
+        let options = RenderOptions {
+            palette: Palette::Monochrome,
+            cursor: false,
+            scale: 3,
+        };
+        let image = rasterize(&screen("_"), &options);
+        assert_eq!((image.width, image.height), (WIDTH * 3, HEIGHT * 3));
+        // The underscore is on the bottom row of the glyph
+        let y = 7 * 3;
+        assert_eq!(image.pixels[y * image.width + 2], [0xff; 3]);
+        assert_eq!(image.pixels[y * image.width + 3], [0x00; 3]);
+
+        let ppm = image.to_ppm();
+        let header = format!("P6\n{} {}\n255\n", WIDTH * 3, HEIGHT * 3);
+        assert!(ppm.starts_with(header.as_bytes()));
+        assert_eq!(ppm.len(), header.len() + WIDTH * HEIGHT * 9 * 3);
+    }
+
+    #[test]
+    fn test_png() {
+        let image = rasterize(&screen("DATAPOINT 2200"), &RenderOptions::default());
+        let png = image.to_png();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+
+        // Check every chunk, and put the stored blocks back together
+        let mut offset = 8;
+        let mut kinds = Vec::new();
+        let mut zlib = Vec::new();
+        while offset < png.len() {
+            let len = u32::from_be_bytes(png[offset..offset + 4].try_into().unwrap()) as usize;
+            let body = &png[offset + 4..offset + 8 + len];
+            let crc =
+                u32::from_be_bytes(png[offset + 8 + len..offset + 12 + len].try_into().unwrap());
+            assert_eq!(crc32(body), crc);
+            kinds.push(String::from_utf8(body[..4].to_vec()).unwrap());
+            if &body[..4] == b"IDAT" {
+                zlib.extend_from_slice(&body[4..]);
+            }
+            offset += 12 + len;
+        }
+        assert_eq!(kinds, ["IHDR", "IDAT", "IEND"]);
+
+        let mut raw = Vec::new();
+        let mut pos = 2;
+        loop {
+            let last = zlib[pos] & 1 != 0;
+            let len = u16::from_le_bytes([zlib[pos + 1], zlib[pos + 2]]) as usize;
+            raw.extend_from_slice(&zlib[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(zlib[pos..], adler32(&raw).to_be_bytes());
+
+        let rows: Vec<_> = raw.chunks(1 + image.width * 3).collect();
+        assert_eq!(rows.len(), image.height);
+        for (row, pixels) in rows.iter().zip(image.pixels.chunks(image.width)) {
+            assert_eq!(row[0], 0);
+            assert_eq!(row[1..], pixels.concat()[..]);
+        }
+    }
+
+    #[test]
+    fn test_svg() {
+        let svg = to_svg(&screen("-"), &RenderOptions::default());
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains(r##"fill="#33ff66""##));
+        // The dash is a single run of five dots
+        assert!(svg.contains(r#"<rect x="1" y="4" width="5" height="1"/>"#));
+        assert_eq!(svg.matches("<rect").count(), 2);
+    }
+}
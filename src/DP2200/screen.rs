@@ -1,98 +1,252 @@
+use std::any::Any;
+
+use log::warn;
+
+use super::{charset, peripheral::Peripheral};
+
 #[derive(Debug, Clone)]
 pub struct Cursor {
     line: usize,
     character: usize,
 }
+// What has changed on the screen since the last time it was asked, so a front end
+// only has to redraw those parts. Changes made directly to the buffer are not seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ScreenChanges {
+    // Bit n is set when something on line n is different
+    pub lines: u16,
+    // The cursor moved, or was turned on or off
+    pub cursor: bool,
+}
+
+impl ScreenChanges {
+    // Everything, for a screen nothing has been drawn from yet
+    pub fn all() -> ScreenChanges {
+        ScreenChanges {
+            lines: (1 << 12) - 1,
+            cursor: true,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines == 0 && !self.cursor
+    }
+
+    pub fn dirty_lines(&self) -> impl Iterator<Item = usize> {
+        let lines = self.lines;
+        (0..12).filter(move |l| lines & (1 << l) != 0)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Screen {
-    pub buffer: [[char; 80]; 12],
+    // The codes written by the cpu, shown through the character generator
+    pub buffer: [[u8; 80]; 12],
     pub cursor: Cursor,
     pub cursor_enabled: bool,
+    // Databus clocks left before the screen takes the next character
+    busy: usize,
+    changes: ScreenChanges,
+    // Unknown control word bits a warning has been logged for, each is only logged once
+    warned_bits: u8,
+    // A write while busy has been logged
+    warned_busy: bool,
 }
 
 pub const SCREEN_ADDR: u8 = 0o341;
 
+// Com1 bits. None of these are checked against the manual yet: erase line and erase frame
+// are where the original code had them, roll up is a guess, and cursor on is where the
+// original code had it. See "The screen control word" in understanding_the_machine.md.
+const ERASE_LINE: u8 = 1 << 1;
+const ERASE_FRAME: u8 = 1 << 2;
+const ROLL_UP: u8 = 1 << 3;
+const CURSOR_ON: u8 = 1 << 4;
+// Bits 0, 5, 6 and 7 are not known, so they are not emulated
+const KNOWN_BITS: u8 = ERASE_LINE | ERASE_FRAME | ROLL_UP | CURSOR_ON;
+
+// Busy time in databus clocks. The manual only says to wait for write ready, so these
+// are estimates: a character is stored in about 100us, and erasing or rolling takes
+// the display a whole frame at 60Hz. Until the times are known, a character written
+// while the screen is busy is still stored.
+const WRITE_CLOCKS: usize = 16;
+const FRAME_CLOCKS: usize = 2560;
+
 impl Screen {
     pub fn new() -> Screen {
         Screen {
-            buffer: [[' '; 80]; 12],
+            buffer: [[b' '; 80]; 12],
             cursor: Cursor {
                 line: 0,
                 character: 0,
             },
             cursor_enabled: false,
+            busy: 0,
+            changes: ScreenChanges::all(),
+            warned_bits: 0,
+            warned_busy: false,
         }
     }
 
-    pub fn clock(&self) {}
-
-    pub fn strobe(&self) {}
-
-    pub fn get_data(&self) -> u8 {
-        0
-    }
-
     pub fn get_screen(&self) -> String {
         let mut s = String::new();
 
         for l in 0..12 {
-            for c in 0..80 {
-                s.push(self.buffer[l][c]);
-            }
+            s.push_str(&self.get_line(l));
             s.push('\n');
         }
         s
     }
 
-    pub fn get_status(&self) -> u8 {
-        1 // Write ready is always true
+    pub fn get_line(&self, line: usize) -> String {
+        (0..80).map(|c| self.get_char(line, c)).collect()
     }
 
-    pub fn write_data(&mut self, data: u8) {
-        self.buffer[self.cursor.line][self.cursor.character] = data as char;
+    // What the screen shows at a position
+    pub fn get_char(&self, line: usize, character: usize) -> char {
+        charset::display(self.buffer[line][character])
     }
 
     pub fn set_horizontal(&mut self, data: u8) {
-        if data < 80 {
+        if data < 80 && data as usize != self.cursor.character {
             self.cursor.character = data as usize;
+            self.changes.cursor = true;
         }
     }
 
     pub fn set_vertical(&mut self, data: u8) {
-        if data < 12 {
+        if data < 12 && data as usize != self.cursor.line {
             self.cursor.line = data as usize;
+            self.changes.cursor = true;
         }
     }
 
+    // Changes since the last call
+    pub fn take_changes(&mut self) -> ScreenChanges {
+        std::mem::take(&mut self.changes)
+    }
+
+    // Peek without clearing, to wait for the screen to change
+    pub fn has_changes(&self) -> bool {
+        !self.changes.is_empty()
+    }
+
+    // Line and character of the cursor
+    pub fn cursor_position(&self) -> (usize, usize) {
+        (self.cursor.line, self.cursor.character)
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_enabled
+    }
+
+    // Com1 control word:
+    // bit 1: erase from the cursor to the end of the line
+    // bit 2: erase from the cursor to the end of the frame
+    // bit 3: roll the screen up one line
+    // bit 4: cursor on, it is turned off by any control word without this bit
+    // The other bits are ignored.
     pub fn control_word(&mut self, data: u8) {
-        if data & (1 << 1) != 0 {
-            // Erase form curser to end of line
+        let unknown = data & !KNOWN_BITS & !self.warned_bits;
+        if unknown != 0 {
+            warn!(
+                "Screen control word {:#04x} has unknown bits {:#04x}, they are ignored",
+                data, unknown
+            );
+            self.warned_bits |= unknown;
+        }
+        let before = self.buffer;
+        if data & ERASE_LINE != 0 {
             for c in self.cursor.character..80 {
-                self.buffer[self.cursor.line][c] = ' ';
+                self.buffer[self.cursor.line][c] = b' ';
             }
         }
-        if data & (1 << 2) != 0 {
-            // Erase form curser to end of frame
-            for c in self.cursor.character..80 {
-                for l in self.cursor.line..12 {
-                    self.buffer[l][c] = ' ';
+        if data & ERASE_FRAME != 0 {
+            // The rest of the cursor line, and every line below it
+            for l in self.cursor.line..12 {
+                let first = if l == self.cursor.line {
+                    self.cursor.character
+                } else {
+                    0
+                };
+                for c in first..80 {
+                    self.buffer[l][c] = b' ';
                 }
             }
         }
 
-        if data & (1 << 4) != 0 {
-            // Roll up whole page
-            for c in 0..80 {
-                for l in 0..11 {
-                    self.buffer[l][c] = self.buffer[l + 1][c];
-                }
+        if data & ROLL_UP != 0 {
+            // The cursor stays where it is, only the text moves
+            for l in 0..11 {
+                self.buffer[l] = self.buffer[l + 1];
             }
-
-            for c in 0..80 {
-                self.buffer[11][c] = ' ';
+            self.buffer[11] = [b' '; 80];
+        }
+        if data & (ERASE_LINE | ERASE_FRAME | ROLL_UP) != 0 {
+            self.busy = self.busy.max(FRAME_CLOCKS);
+        }
+        for (l, line) in before.iter().enumerate() {
+            if self.buffer[l] != *line {
+                self.changes.lines |= 1 << l;
             }
         }
-        self.cursor_enabled = data & (1 << 4) != 0
+
+        let cursor_enabled = data & CURSOR_ON != 0;
+        if cursor_enabled != self.cursor_enabled {
+            self.cursor_enabled = cursor_enabled;
+            self.changes.cursor = true;
+        }
+    }
+}
+
+impl Peripheral for Screen {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn get_status(&mut self) -> u8 {
+        // Write ready
+        (self.busy == 0) as u8
+    }
+
+    fn write_data(&mut self, data: u8) {
+        if self.busy > 0 && !self.warned_busy {
+            warn!(
+                "Write of {:#04x} to the screen while it was busy for {} more clocks",
+                data, self.busy
+            );
+            self.warned_busy = true;
+        }
+        let cell = &mut self.buffer[self.cursor.line][self.cursor.character];
+        if *cell != data {
+            *cell = data;
+            self.changes.lines |= 1 << self.cursor.line;
+        }
+        self.busy = WRITE_CLOCKS;
+    }
+
+    fn com1(&mut self, data: u8) {
+        self.control_word(data);
+    }
+
+    fn com2(&mut self, data: u8) {
+        self.set_horizontal(data);
+    }
+
+    fn com3(&mut self, data: u8) {
+        self.set_vertical(data);
+    }
+
+    fn clock(&mut self) {
+        self.busy = self.busy.saturating_sub(1);
+    }
+
+    fn reset(&mut self) {
+        *self = Screen::new();
     }
 }
 
@@ -101,3 +255,187 @@ impl Default for Screen {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every position holds a letter for its line, so moved and erased text shows
+    fn filled() -> Screen {
+        let mut screen = Screen::new();
+        for l in 0..12 {
+            screen.buffer[l] = [b'a' + l as u8; 80];
+        }
+        screen.set_vertical(5);
+        screen.set_horizontal(40);
+        screen
+    }
+
+    #[test]
+    fn test_erase_line() {
+        let mut screen = filled();
+        screen.control_word(ERASE_LINE);
+
+        assert_eq!(screen.buffer[5][..40], [b'f'; 40]);
+        assert_eq!(screen.buffer[5][40..], [b' '; 40]);
+        assert_eq!(screen.buffer[4], [b'e'; 80]);
+        assert_eq!(screen.buffer[6], [b'g'; 80]);
+    }
+
+    #[test]
+    fn test_erase_frame() {
+        let mut screen = filled();
+        screen.control_word(ERASE_FRAME);
+
+        assert_eq!(screen.buffer[4], [b'e'; 80]);
+        assert_eq!(screen.buffer[5][..40], [b'f'; 40]);
+        assert_eq!(screen.buffer[5][40..], [b' '; 40]);
+        // Whole lines below the cursor, not only the columns right of it
+        for l in 6..12 {
+            assert_eq!(screen.buffer[l], [b' '; 80]);
+        }
+    }
+
+    #[test]
+    fn test_roll_up() {
+        let mut screen = filled();
+        screen.control_word(ROLL_UP);
+
+        for l in 0..11 {
+            assert_eq!(screen.buffer[l], [b'b' + l as u8; 80]);
+        }
+        assert_eq!(screen.buffer[11], [b' '; 80]);
+        assert_eq!(screen.cursor_position(), (5, 40));
+        assert!(!screen.cursor_visible());
+    }
+
+    #[test]
+    fn test_cursor_on() {
+        let mut screen = filled();
+        screen.control_word(CURSOR_ON);
+        assert!(screen.cursor_visible());
+        assert_eq!(screen.cursor_position(), (5, 40));
+        assert_eq!(screen.get_screen(), filled().get_screen());
+
+        // Rolling with the cursor on, as CTOS does at the bottom of the screen
+        screen.control_word(ROLL_UP | CURSOR_ON);
+        assert!(screen.cursor_visible());
+        assert_eq!(screen.buffer[0], [b'b'; 80]);
+
+        screen.control_word(0);
+        assert!(!screen.cursor_visible());
+    }
+
+    #[test]
+    fn test_unknown_bits() {
+        let mut screen = filled();
+        screen.take_changes();
+        screen.control_word(!KNOWN_BITS | CURSOR_ON);
+
+        assert_eq!(screen.get_screen(), filled().get_screen());
+        assert!(screen.cursor_visible());
+        assert_eq!(screen.get_status() & 1, 1);
+
+        // Each unknown bit is only warned about once
+        assert_eq!(screen.warned_bits, !KNOWN_BITS);
+        screen.control_word(1 << 0);
+        assert_eq!(screen.warned_bits, !KNOWN_BITS);
+    }
+
+    fn wait(screen: &mut Screen) -> usize {
+        let mut clocks = 0;
+        while screen.get_status() & 1 == 0 {
+            screen.clock();
+            clocks += 1;
+        }
+        clocks
+    }
+
+    #[test]
+    fn test_write_ready() {
+        let mut screen = Screen::new();
+        assert_eq!(screen.get_status(), 1);
+
+        screen.write_data(b'A');
+        assert_eq!(screen.get_status(), 0);
+        assert_eq!(wait(&mut screen), WRITE_CLOCKS);
+
+        // Written while busy, the times are not known well enough to lose it
+        screen.set_horizontal(1);
+        screen.write_data(b'B');
+        screen.set_horizontal(2);
+        screen.write_data(b'C');
+        assert!(screen.warned_busy);
+        assert_eq!(wait(&mut screen), WRITE_CLOCKS);
+        assert_eq!(&screen.get_screen()[..4], "ABC ");
+    }
+
+    #[test]
+    fn test_control_busy() {
+        let mut screen = filled();
+        screen.control_word(CURSOR_ON);
+        assert_eq!(screen.get_status(), 1);
+
+        for bit in [ERASE_LINE, ERASE_FRAME, ROLL_UP] {
+            screen.control_word(bit | CURSOR_ON);
+            assert_eq!(wait(&mut screen), FRAME_CLOCKS);
+        }
+    }
+
+    #[test]
+    fn test_raw_codes() {
+        let mut screen = Screen::new();
+        screen.write_data(0xe1);
+        screen.set_horizontal(1);
+        wait(&mut screen);
+        screen.write_data(0x0d);
+
+        // Kept as written, shown through the character generator
+        assert_eq!(screen.buffer[0][..2], [0xe1, 0x0d]);
+        assert_eq!(screen.get_char(0, 0), 'A');
+        assert_eq!(screen.get_char(0, 1), ' ');
+    }
+
+    #[test]
+    fn test_changes() {
+        let mut screen = Screen::new();
+        // A new screen has everything to draw
+        assert_eq!(screen.take_changes(), ScreenChanges::all());
+        assert!(!screen.has_changes());
+
+        screen.set_vertical(3);
+        screen.write_data(b'A');
+        let changes = screen.take_changes();
+        assert_eq!(changes.dirty_lines().collect::<Vec<_>>(), [3]);
+        assert!(changes.cursor);
+
+        // Nothing that shows is different
+        wait(&mut screen);
+        screen.write_data(b'A');
+        screen.set_vertical(3);
+        screen.control_word(ERASE_LINE | ERASE_FRAME);
+        assert!(screen.take_changes().dirty_lines().eq([3]));
+        screen.control_word(ERASE_FRAME);
+        assert!(!screen.has_changes());
+
+        screen.control_word(CURSOR_ON);
+        assert_eq!(
+            screen.take_changes(),
+            ScreenChanges {
+                lines: 0,
+                cursor: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_roll_changes() {
+        let mut screen = Screen::new();
+        screen.buffer[5] = [b'x'; 80];
+        screen.take_changes();
+
+        // The text moves from line 5 to line 4
+        screen.control_word(ROLL_UP);
+        assert!(screen.take_changes().dirty_lines().eq([4, 5]));
+    }
+}
@@ -0,0 +1,218 @@
+// The record format used by CTOS on cassette tapes.
+// See "The tape format" in understanding_the_machine.md for how this was worked out.
+
+use super::cassette::{read_tap_blocks, write_tap_blocks, TapError};
+
+pub const FILE_MARKER: [u8; 2] = [0x81, 0x7e];
+pub const NUMERIC: [u8; 2] = [0xc3, 0x3c];
+pub const SYMBOLIC: [u8; 2] = [0xe7, 0x18];
+
+// Length of the header in front of the payload of numeric and symbolic records
+pub const HEADER_LEN: usize = 8;
+
+// The header of a data record:
+// [type, !type, parity, rotation, address high, address low, !address high, !address low]
+// The parity is the xor of every byte after the check bytes, the rotation is the same
+// bytes xored into a register that is rotated left before each byte, starting from the end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataRecord {
+    pub parity: u8,
+    pub rotation: u8,
+    pub address: u16,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    // The first record on the tape is loaded by the restart logic, and has no header
+    Boot(Vec<u8>),
+    FileMarker(u8),
+    Numeric(DataRecord),
+    Symbolic(DataRecord),
+    Unknown(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Tape {
+    pub records: Vec<Record>,
+}
+
+fn check_bytes(body: &[u8]) -> (u8, u8) {
+    let parity = body.iter().fold(0, |p, b| p ^ b);
+    let rotation = body.iter().rev().fold(0, |r: u8, b| r.rotate_left(1) ^ b);
+    (parity, rotation)
+}
+
+impl DataRecord {
+    pub fn new(address: u16, payload: Vec<u8>) -> DataRecord {
+        let mut record = DataRecord {
+            parity: 0,
+            rotation: 0,
+            address,
+            payload,
+        };
+        (record.parity, record.rotation) = check_bytes(&record.body());
+        record
+    }
+
+    // The address bytes and the payload, which is what the check bytes cover
+    fn body(&self) -> Vec<u8> {
+        let [high, low] = self.address.to_be_bytes();
+        let mut body = vec![high, low, !high, !low];
+        body.extend_from_slice(&self.payload);
+        body
+    }
+
+    fn parse(block: &[u8]) -> Option<DataRecord> {
+        if block.len() < HEADER_LEN || block[4] != !block[6] || block[5] != !block[7] {
+            return None;
+        }
+
+        Some(DataRecord {
+            parity: block[2],
+            rotation: block[3],
+            address: u16::from_be_bytes([block[4], block[5]]),
+            payload: block[HEADER_LEN..].to_vec(),
+        })
+    }
+
+    pub fn checks_ok(&self) -> bool {
+        check_bytes(&self.body()) == (self.parity, self.rotation)
+    }
+
+    fn to_bytes(&self, kind: [u8; 2]) -> Vec<u8> {
+        let mut block = vec![kind[0], kind[1], self.parity, self.rotation];
+        block.append(&mut self.body());
+        block
+    }
+}
+
+impl Record {
+    pub fn parse(block: &[u8], first: bool) -> Record {
+        let res = if block.starts_with(&FILE_MARKER) {
+            match block {
+                [_, _, file, check] if *file == !*check => Some(Record::FileMarker(*file)),
+                _ => None,
+            }
+        } else if block.starts_with(&NUMERIC) {
+            DataRecord::parse(block).map(Record::Numeric)
+        } else if block.starts_with(&SYMBOLIC) {
+            DataRecord::parse(block).map(Record::Symbolic)
+        } else if first {
+            Some(Record::Boot(block.to_vec()))
+        } else {
+            None
+        };
+
+        res.unwrap_or_else(|| Record::Unknown(block.to_vec()))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Record::Boot(data) => data.clone(),
+            Record::FileMarker(file) => vec![FILE_MARKER[0], FILE_MARKER[1], *file, !*file],
+            Record::Numeric(record) => record.to_bytes(NUMERIC),
+            Record::Symbolic(record) => record.to_bytes(SYMBOLIC),
+            Record::Unknown(data) => data.clone(),
+        }
+    }
+}
+
+impl Tape {
+    pub fn parse(tap_file: &[u8], strict: bool) -> Result<Tape, TapError> {
+        let blocks = read_tap_blocks(tap_file, strict)?;
+        let records = blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| Record::parse(block, i == 0))
+            .collect();
+
+        Ok(Tape { records })
+    }
+
+    pub fn to_tap(&self) -> Vec<u8> {
+        let blocks: Vec<Vec<u8>> = self.records.iter().map(|r| r.to_bytes()).collect();
+        write_tap_blocks(&blocks)
+    }
+
+    pub fn boot(&self) -> Option<&[u8]> {
+        match self.records.first() {
+            Some(Record::Boot(data)) => Some(data),
+            _ => None,
+        }
+    }
+
+    // Every record paired with the number of the last file marker before it
+    pub fn with_files(&self) -> impl Iterator<Item = (Option<u8>, &Record)> {
+        self.records.iter().scan(None, |file, record| {
+            if let Record::FileMarker(no) = record {
+                *file = Some(*no);
+            }
+            Some((*file, record))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_games() {
+        let tap_file = include_bytes!("../../Games.tap");
+        let tape = Tape::parse(tap_file, true).unwrap();
+
+        assert_eq!(tape.records.len(), 257);
+        assert_eq!(tape.boot().unwrap().len(), 512);
+        assert_eq!(tape.records[1], Record::FileMarker(0));
+        match &tape.records[2] {
+            Record::Numeric(record) => {
+                assert_eq!(record.address, 0x1800);
+                assert_eq!(record.payload.len(), 248);
+            }
+            r => panic!("Expected numeric record, got {:?}", r),
+        }
+
+        for record in tape.records.iter() {
+            assert!(!matches!(record, Record::Unknown(_)));
+            if let Record::Numeric(record) = record {
+                assert!(record.checks_ok());
+            }
+        }
+
+        assert_eq!(tape.to_tap(), tap_file.to_vec());
+    }
+
+    #[test]
+    fn test_with_files() {
+        let tap_file = include_bytes!("../../tstdis1.1_3-75.fixed.tap");
+        let tape = Tape::parse(tap_file, true).unwrap();
+        let files: Vec<Option<u8>> = tape.with_files().map(|(file, _)| file).collect();
+
+        assert_eq!(files[0], None);
+        assert_eq!(files[1..14], [Some(0)].repeat(13));
+        assert_eq!(files[14], Some(0x20));
+    }
+
+    #[test]
+    fn test_new_data_record() {
+        let record = Record::Numeric(DataRecord::new(0x1800, Vec::new()));
+        assert_eq!(
+            record.to_bytes(),
+            vec![0xc3, 0x3c, 0x00, 0x78, 0x18, 0x00, 0xe7, 0xff]
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown() {
+        assert_eq!(
+            Record::parse(&[0x81, 0x7e, 1, 1], false),
+            Record::Unknown(vec![0x81, 0x7e, 1, 1])
+        );
+        assert_eq!(
+            Record::parse(&[0xc3, 0x3c, 0], false),
+            Record::Unknown(vec![0xc3, 0x3c, 0])
+        );
+        assert_eq!(Record::parse(&[1, 2], true), Record::Boot(vec![1, 2]));
+    }
+}
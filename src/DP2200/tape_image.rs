@@ -0,0 +1,319 @@
+// A tape image that keeps everything about a tape, not only its records, so a tape
+// can be put on the shelf and loaded again exactly as it was.
+//
+// All numbers are little endian:
+//   magic      "DP2200TAPE"
+//   version    u8, currently 2
+//   flags      u8, bit 0 is set when the write protect tab is removed
+//   head       u32, position of the head in byte times from the start of the tape
+//   label      u32 length, then UTF-8. Version 1 has a u16 length.
+//   notes      u32 length, then UTF-8
+//   segments   u32 count, then for each segment
+//     gap      0x00, u32 length in byte times
+//     block    0x01, u32 length, then the bytes
+
+use std::fmt;
+
+use super::cassette::{parse_tap, serialize_tap, CassetteData, TapError};
+
+pub const MAGIC: &[u8] = b"DP2200TAPE";
+pub const VERSION: u8 = 2;
+pub const EXTENSION: &str = "dpt";
+
+const WRITE_PROTECTED: u8 = 1 << 0;
+const GAP: u8 = 0x00;
+const BLOCK: u8 = 0x01;
+// Byte times, gaps and data together. Far more than fits on a cassette, it only stops
+// a damaged length, or a write that never ends, from filling the memory.
+pub const MAX_TAPE_LENGTH: usize = 1 << 24;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TapeImage {
+    pub label: String,
+    pub write_protected: bool,
+    pub head_pos: usize,
+    pub notes: String,
+    pub data: Vec<CassetteData>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageError {
+    // Does not start with MAGIC
+    NotImage,
+    // Written by a newer version of the emulator
+    UnsupportedVersion(u8),
+    Truncated { offset: usize },
+    InvalidText { offset: usize },
+    UnknownSegment { offset: usize, kind: u8 },
+    // The segment at offset would make the tape longer than MAX_TAPE_LENGTH
+    TooLong { offset: usize },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::NotImage => write!(f, "Not a tape image"),
+            ImageError::UnsupportedVersion(version) => write!(
+                f,
+                "Tape image version {} is newer than the supported version {}",
+                version, VERSION
+            ),
+            ImageError::Truncated { offset } => write!(f, "Tape image ends at byte {}", offset),
+            ImageError::InvalidText { offset } => {
+                write!(f, "Text at byte {} is not valid UTF-8", offset)
+            }
+            ImageError::UnknownSegment { offset, kind } => {
+                write!(f, "Unknown segment type {:#04x} at byte {}", kind, offset)
+            }
+            ImageError::TooLong { offset } => write!(
+                f,
+                "Segment at byte {} makes the tape longer than {} byte times",
+                offset, MAX_TAPE_LENGTH
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+pub fn is_image(file: &[u8]) -> bool {
+    file.starts_with(MAGIC)
+}
+
+struct Reader<'a> {
+    file: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ImageError> {
+        let bytes = self
+            .file
+            .get(self.offset..self.offset + len)
+            .ok_or(ImageError::Truncated {
+                offset: self.file.len(),
+            })?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ImageError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<usize, ImageError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+    }
+
+    fn u32(&mut self) -> Result<usize, ImageError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn text(&mut self, len: usize) -> Result<String, ImageError> {
+        let offset = self.offset;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ImageError::InvalidText { offset })
+    }
+}
+
+fn push_u32(file: &mut Vec<u8>, value: usize) {
+    file.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+impl TapeImage {
+    pub fn parse(file: &[u8]) -> Result<TapeImage, ImageError> {
+        if !is_image(file) {
+            return Err(ImageError::NotImage);
+        }
+        let mut reader = Reader {
+            file,
+            offset: MAGIC.len(),
+        };
+
+        let version = reader.u8()?;
+        if version > VERSION {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+        let flags = reader.u8()?;
+        let head_pos = reader.u32()?;
+        let len = if version < 2 {
+            reader.u16()?
+        } else {
+            reader.u32()?
+        };
+        let label = reader.text(len)?;
+        let len = reader.u32()?;
+        let notes = reader.text(len)?;
+
+        let mut data = Vec::new();
+        for _ in 0..reader.u32()? {
+            let offset = reader.offset;
+            let kind = reader.u8()?;
+            let len = reader.u32()?;
+            if len > MAX_TAPE_LENGTH - data.len() {
+                return Err(ImageError::TooLong { offset });
+            }
+            match kind {
+                GAP => data.extend([CassetteData::Gap].repeat(len)),
+                BLOCK => data.extend(reader.take(len)?.iter().map(|b| CassetteData::Data(*b))),
+                kind => return Err(ImageError::UnknownSegment { offset, kind }),
+            }
+        }
+
+        Ok(TapeImage {
+            label,
+            write_protected: flags & WRITE_PROTECTED != 0,
+            head_pos,
+            notes,
+            data,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        file.push(VERSION);
+        file.push(if self.write_protected {
+            WRITE_PROTECTED
+        } else {
+            0
+        });
+        push_u32(&mut file, self.head_pos);
+        push_u32(&mut file, self.label.len());
+        file.extend_from_slice(self.label.as_bytes());
+        push_u32(&mut file, self.notes.len());
+        file.extend_from_slice(self.notes.as_bytes());
+
+        // Runs of gaps, and the blocks between them
+        let mut segments: Vec<&[CassetteData]> = Vec::new();
+        let mut start = 0;
+        for i in 1..=self.data.len() {
+            let gap = |i: usize| self.data.get(i).map(|d| *d == CassetteData::Gap);
+            if i == self.data.len() || gap(i) != gap(i - 1) {
+                segments.push(&self.data[start..i]);
+                start = i;
+            }
+        }
+        push_u32(&mut file, segments.len());
+        for segment in segments {
+            let gap = segment[0] == CassetteData::Gap;
+            file.push(if gap { GAP } else { BLOCK });
+            push_u32(&mut file, segment.len());
+            if !gap {
+                file.extend(segment.iter().map(|d| match d {
+                    CassetteData::Data(data) => *data,
+                    CassetteData::Gap => unreachable!(),
+                }));
+            }
+        }
+        file
+    }
+
+    // A tape with the records of a tap file, with the usual gaps between them
+    pub fn from_tap(tap_file: &[u8], strict: bool) -> Result<TapeImage, TapError> {
+        Ok(TapeImage {
+            data: parse_tap(tap_file, strict)?,
+            ..TapeImage::default()
+        })
+    }
+
+    // Only the records are kept, a tap file has no room for anything else
+    pub fn to_tap(&self) -> Vec<u8> {
+        serialize_tap(&self.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut data = [CassetteData::Gap].repeat(3);
+        data.extend([CassetteData::Data(0xbe), CassetteData::Data(0xef)]);
+        data.extend([CassetteData::Gap].repeat(17));
+        data.push(CassetteData::Data(0x42));
+        let image = TapeImage {
+            label: "Games, side A".to_string(),
+            write_protected: true,
+            head_pos: 5,
+            notes: "Found in a box\nSecond block is damaged".to_string(),
+            data,
+        };
+        let file = image.to_bytes();
+
+        assert!(is_image(&file));
+        assert_eq!(TapeImage::parse(&file), Ok(image));
+    }
+
+    #[test]
+    fn test_long_label() {
+        let image = TapeImage {
+            label: "ø".repeat(40000),
+            ..TapeImage::default()
+        };
+        assert_eq!(TapeImage::parse(&image.to_bytes()), Ok(image));
+    }
+
+    #[test]
+    fn test_version_1() {
+        // The label had a 16 bit length
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&[1, WRITE_PROTECTED, 2, 0, 0, 0]);
+        file.extend_from_slice(&[4, 0]);
+        file.extend_from_slice(b"Demo");
+        file.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0, GAP, 3, 0, 0, 0]);
+
+        let image = TapeImage::parse(&file).unwrap();
+        assert_eq!(image.label, "Demo");
+        assert!(image.write_protected);
+        assert_eq!(image.head_pos, 2);
+        assert_eq!(image.data, [CassetteData::Gap].repeat(3));
+    }
+
+    #[test]
+    fn test_tap_conversion() {
+        let tap_file = include_bytes!("../../Games.tap");
+        let image = TapeImage::from_tap(tap_file, true).unwrap();
+        let image = TapeImage::parse(&image.to_bytes()).unwrap();
+
+        assert_eq!(image.to_tap(), tap_file.to_vec());
+        assert_eq!(TapeImage::from_tap(&image.to_tap(), true), Ok(image));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            TapeImage::parse(&[1, 0, 0, 0, 0xbe, 1, 0, 0, 0]),
+            Err(ImageError::NotImage)
+        );
+
+        let mut file = TapeImage::default().to_bytes();
+        file[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            TapeImage::parse(&file),
+            Err(ImageError::UnsupportedVersion(VERSION + 1))
+        );
+
+        let image = TapeImage::from_tap(&[2, 0, 0, 0, 0xbe, 0xef, 2, 0, 0, 0], true).unwrap();
+        let file = image.to_bytes();
+        assert_eq!(
+            TapeImage::parse(&file[..file.len() - 1]),
+            Err(ImageError::Truncated {
+                offset: file.len() - 1
+            })
+        );
+
+        // A gap of u32::MAX byte times is refused before anything is allocated
+        let mut file = TapeImage::default().to_bytes();
+        let count = file.len() - 4;
+        file[count..].copy_from_slice(&1u32.to_le_bytes());
+        file.push(GAP);
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            TapeImage::parse(&file),
+            Err(ImageError::TooLong { offset: count + 4 })
+        );
+    }
+}
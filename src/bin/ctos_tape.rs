@@ -0,0 +1,135 @@
+// Treat a CTOS cassette image as a file system.
+//
+//   ctos_tape list <tape.tap>
+//   ctos_tape extract <tape.tap> <file> <out>
+//   ctos_tape build <out.tap> <boot> <file>=<path>[@<load>[,<entry>]]...
+//
+// extract writes the records of the file as they are when out ends with .tap, otherwise
+// a best effort at the text of its symbolic records, or the payloads of its numeric records.
+// build takes the boot block from the first record of boot when it is a .tap,
+// otherwise from the whole file. Host files ending with .tap are copied record
+// for record, and other files are split into numeric records loaded at the given address.
+
+use std::{
+    env,
+    fs::{read, write},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use datapoint2200::DP2200::{
+    ctos::TapeFile,
+    tape::{Record, Tape},
+};
+use parse_int::parse;
+
+const USAGE: &str = "Usage:
+    ctos_tape list <tape.tap>
+    ctos_tape extract <tape.tap> <file> <out>
+    ctos_tape build <out.tap> <boot> <file>=<path>[@<load>[,<entry>]]...";
+
+fn is_tap(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("tap"))
+}
+
+fn read_tape(path: &str) -> Result<Tape> {
+    let tap_file = read(path).with_context(|| format!("Could not read {}", path))?;
+    Tape::parse(&tap_file, false).with_context(|| format!("Could not parse {}", path))
+}
+
+fn list(path: &str) -> Result<()> {
+    let tape = read_tape(path)?;
+    match tape.boot() {
+        Some(boot) => println!("Boot block: {} bytes", boot.len()),
+        None => println!("No boot block"),
+    }
+
+    println!("File  Numeric  Symbolic  Other  Bytes  Entry");
+    for file in tape.files() {
+        let counts = file.counts();
+        let entry = file
+            .entry()
+            .map_or("-".to_string(), |addr| format!("{:#06x}", addr));
+        println!(
+            "{:>4}  {:>7}  {:>8}  {:>5}  {:>5}  {}",
+            file.number, counts.numeric, counts.symbolic, counts.other, counts.bytes, entry
+        );
+    }
+    Ok(())
+}
+
+fn extract(path: &str, number: &str, out: &str) -> Result<()> {
+    let number: u8 = parse(number).with_context(|| format!("Invalid file number {}", number))?;
+    let file = match read_tape(path)?.file(number) {
+        Some(file) => file,
+        None => bail!("No file {} on {}", number, path),
+    };
+
+    let data = if is_tap(out) {
+        file.to_tap()
+    } else if file.counts().symbolic > 0 {
+        file.text().into_bytes()
+    } else {
+        file.numeric_image()
+    };
+    write(out, data).with_context(|| format!("Could not write {}", out))
+}
+
+// <file>=<path>[@<load>[,<entry>]]
+fn host_file(arg: &str) -> Result<TapeFile> {
+    let (number, rest) = match arg.split_once('=') {
+        Some(split) => split,
+        None => bail!("Expected <file>=<path> but got {}", arg),
+    };
+    let number: u8 = parse(number).with_context(|| format!("Invalid file number {}", number))?;
+    let (path, addresses) = match rest.split_once('@') {
+        Some((path, addresses)) => (path, Some(addresses)),
+        None => (rest, None),
+    };
+    let data = read(path).with_context(|| format!("Could not read {}", path))?;
+
+    if is_tap(path) {
+        return TapeFile::from_tap(number, &data, false)
+            .with_context(|| format!("Could not parse {}", path));
+    }
+
+    // The framing of symbolic records is not known well enough to write them
+    let addresses = match addresses {
+        Some(addresses) => addresses,
+        None => bail!("{} needs a load address, <file>=<path>@<load>", path),
+    };
+    let (load, entry) = addresses.split_once(',').unwrap_or((addresses, addresses));
+    let load: u16 = parse(load).with_context(|| format!("Invalid load address {}", load))?;
+    let entry: u16 = parse(entry).with_context(|| format!("Invalid entry address {}", entry))?;
+    Ok(TapeFile::from_image(number, load, entry, &data))
+}
+
+fn build(out: &str, boot: &str, files: &[String]) -> Result<()> {
+    let boot = if is_tap(boot) {
+        match read_tape(boot)?.records.into_iter().next() {
+            Some(Record::Boot(data)) => data,
+            _ => bail!("No boot block on {}", boot),
+        }
+    } else {
+        read(boot).with_context(|| format!("Could not read {}", boot))?
+    };
+
+    let files = files
+        .iter()
+        .map(|arg| host_file(arg))
+        .collect::<Result<Vec<_>>>()?;
+    let tape = Tape::build(boot, files);
+    write(out, tape.to_tap()).with_context(|| format!("Could not write {}", out))
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>()[..] {
+        ["list", path] => list(path),
+        ["extract", path, number, out] => extract(path, number, out),
+        ["build", out, boot, ..] => build(out, boot, &args[3..]),
+        _ => bail!(USAGE),
+    }
+}
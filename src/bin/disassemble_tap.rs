@@ -0,0 +1,76 @@
+// Disassemble CTOS tapes.
+//
+//   disassemble_tap [--rebuild] <tape.tap>...
+//   disassemble_tap --assemble <listing.asm>...
+//
+// Writes one .asm next to every tape, with the records, the files they belong to
+// and where they are loaded. With --rebuild the listing keeps the record headers,
+// and --assemble turns it back into a .tap with the same records, written as
+// <listing>.rebuilt.tap so the tape it came from is not overwritten.
+
+use std::{
+    env,
+    fs::{read, read_to_string, write},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use datapoint2200::DP2200::{
+    disassembler::{assemble_tape, disassemble_tape, Listing},
+    tape::Tape,
+};
+
+const USAGE: &str = "Usage:
+    disassemble_tap [--rebuild] <tape.tap>...
+    disassemble_tap --assemble <listing.asm>...";
+
+fn disassemble_file(path: &Path, listing: Listing) -> Result<()> {
+    let tap_file = read(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let tape = Tape::parse(&tap_file, false)
+        .with_context(|| format!("Could not parse {}", path.display()))?;
+
+    let outfile = path.with_extension("asm");
+    let asm = format!(
+        "# Disassembly of {}\n{}",
+        path.display(),
+        disassemble_tape(&tape, listing)
+    );
+    write(&outfile, asm).with_context(|| format!("Could not write {}", outfile.display()))?;
+    println!("{} -> {}", path.display(), outfile.display());
+    Ok(())
+}
+
+fn assemble_file(path: &Path) -> Result<()> {
+    let listing =
+        read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let tap_file = assemble_tape(&listing)
+        .with_context(|| format!("Could not assemble {}", path.display()))?;
+
+    let outfile = path.with_extension("rebuilt.tap");
+    write(&outfile, tap_file).with_context(|| format!("Could not write {}", outfile.display()))?;
+    println!("{} -> {}", path.display(), outfile.display());
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let assemble = args.iter().any(|arg| arg == "--assemble");
+    let listing = if args.iter().any(|arg| arg == "--rebuild") {
+        Listing::Rebuild
+    } else {
+        Listing::Annotated
+    };
+
+    let paths: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+    if paths.is_empty() {
+        bail!(USAGE);
+    }
+    for path in paths {
+        if assemble {
+            assemble_file(Path::new(path))?;
+        } else {
+            disassemble_file(Path::new(path), listing)?;
+        }
+    }
+    Ok(())
+}
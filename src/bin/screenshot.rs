@@ -0,0 +1,100 @@
+// Boot a tape without a terminal, and save what is on the screen as an image.
+//
+//   screenshot <tape> <out.png|out.ppm|out.svg> [<seconds>] [--mono] [--no-cursor] [--scale <n>]
+//              [--record <out.cast>]
+//
+// The machine runs for the given number of emulated seconds, 5 by default, or until it halts.
+// With --record, everything that happens on the screen until then is saved as an asciicast.
+
+use std::{
+    env,
+    fs::{read, write, File},
+    io::BufWriter,
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use datapoint2200::DP2200::{
+    asciicast::Recorder,
+    datapoint::{BootMode, Datapoint},
+    render::{render, ImageFormat, Palette, RenderOptions},
+};
+use parse_int::parse;
+
+const USAGE: &str = "Usage:
+    screenshot <tape> <out.png|out.ppm|out.svg> [<seconds>] [--mono] [--no-cursor] [--scale <n>]
+               [--record <out.cast>]";
+
+// Emulated time between two frames of a recording
+const FRAME_MS: f64 = 20.0;
+
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let mut options = RenderOptions::default();
+    let mut positional = Vec::new();
+    let mut cast = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--mono" => options.palette = Palette::Monochrome,
+            "--no-cursor" => options.cursor = false,
+            "--scale" => {
+                let scale = args.next().ok_or_else(|| anyhow!(USAGE))?;
+                options.scale =
+                    parse(&scale).with_context(|| format!("Invalid scale {}", scale))?;
+            }
+            "--record" => cast = Some(args.next().ok_or_else(|| anyhow!(USAGE))?),
+            _ => positional.push(arg),
+        }
+    }
+    let (tape, out, seconds) = match &positional[..] {
+        [tape, out] => (tape, out, 5.0),
+        [tape, out, seconds] => (
+            tape,
+            out,
+            seconds
+                .parse::<f64>()
+                .with_context(|| format!("Invalid number of seconds {}", seconds))?,
+        ),
+        _ => bail!(USAGE),
+    };
+    let format = ImageFormat::from_path(Path::new(out))
+        .ok_or_else(|| anyhow!("{} does not end with .png, .ppm or .svg", out))?;
+
+    let data = read(tape).with_context(|| format!("Could not read {}", tape))?;
+    let mut machine = Datapoint::build(&data, 1.0);
+    machine
+        .load_cassette(data, BootMode::Restart)
+        .with_context(|| format!("Could not load {}", tape))?;
+
+    let mut recorder = match &cast {
+        Some(cast) => {
+            let out = File::create(cast).with_context(|| format!("Could not create {}", cast))?;
+            let screen = machine.databus.screen_mut();
+            screen.take_changes();
+            Some(Recorder::new(BufWriter::new(out), screen, 0, tape)?)
+        }
+        None => None,
+    };
+
+    let goal = (seconds * 1e9) as u128;
+    while !machine.cpu.halted && machine.clock.emulated_time_ns < goal {
+        let left_ms = (goal - machine.clock.emulated_time_ns) as f64 / 1e6;
+        match recorder.as_mut() {
+            Some(recorder) => {
+                machine.update(left_ms.min(FRAME_MS));
+                let changes = machine.databus.screen_mut().take_changes();
+                let time = machine.clock.emulated_time_ns;
+                recorder.screen(time, machine.databus.screen(), changes)?;
+            }
+            None => {
+                machine.update(left_ms);
+            }
+        }
+    }
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
+
+    let image = render(machine.databus.screen(), &options, format);
+    write(out, image).with_context(|| format!("Could not write {}", out))
+}
@@ -1,10 +1,8 @@
-use core::panic;
-use glob::glob;
 use std::{
     env,
-    fs::{read, remove_file, write, File},
-    io::{self, Read, Write},
-    path::Path,
+    fs::{read, File},
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
 };
 
 
@@ -15,8 +13,12 @@ use ratatui::{
     DefaultTerminal,
 };
 use std::time::Duration;
-pub mod DP2200;
-use DP2200::datapoint;
+use datapoint2200::DP2200::{
+    asciicast::Recorder,
+    cassette::{DeckId, TapeTiming},
+    datapoint::{self, BootMode},
+    keymap::Keymap,
+};
 // fn main() {
 //         let data = read(path).unwrap();
 //         let mut machine = datapoint::Datapoint::build(&data, 1.0);
@@ -24,7 +26,7 @@ use DP2200::datapoint;
 //         while !machine.cpu.halted {
 //             println!("Runnig");
 //             machine.update(10.0);
-//             println!("{}", machine.databus.screen.get_screen());
+//             println!("{}", machine.databus.screen().get_screen());
 //         }
 //     }
 // }
@@ -39,41 +41,119 @@ fn main() -> io::Result<()> {
 
 fn run(mut terminal: DefaultTerminal) -> io::Result<()> {
     let args = env::args().collect::<Vec<_>>();
-    let path = args.get(1).unwrap();
+    let path = args.iter().skip(1).find(|arg| !arg.starts_with("--")).unwrap();
+    let write_back = args.iter().any(|arg| arg == "--write-back");
+    let boot = if args.iter().any(|arg| arg == "--fast-boot") {
+        BootMode::Fast
+    } else {
+        BootMode::Restart
+    };
     let data = read(path).unwrap();
+    // --keymap=<file> changes which host keys press which keys on the 2200
+    let keymap = match args.iter().find_map(|arg| arg.strip_prefix("--keymap=")) {
+        Some(file) => Keymap::parse(&std::fs::read_to_string(file)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        None => Keymap::default(),
+    };
 
     let mut machine = datapoint::Datapoint::build(&data, 1.0);
-    machine.load_cassette(data);
+    if args.iter().any(|arg| arg == "--fast-tape") {
+        machine.databus.cassette_mut().set_timing(TapeTiming::Fast);
+    }
+    machine
+        .load_cassette(data, boot)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    if write_back {
+        let deck = machine.databus.cassette_mut().get_deck(DeckId::Deck1);
+        deck.write_back = Some(PathBuf::from(path));
+    }
 
 
-    let mut i = 0;
+    // F2 asks for the path of a tape to put in deck 2 while the machine runs
+    let mut mount: Option<String> = None;
+    let mut tape_msg = String::new();
+    // Only the lines that changed are fetched from the screen again
+    let mut lines = vec![String::new(); 12];
+    // --record=<file> records the session as an asciicast
+    let mut recorder = match args.iter().find_map(|arg| arg.strip_prefix("--record=")) {
+        Some(cast) => {
+            let out = BufWriter::new(File::create(cast)?);
+            Some(Recorder::new(out, machine.databus.screen(), machine.clock.emulated_time_ns, path)?)
+        }
+        None => None,
+    };
     while !machine.cpu.halted {
         machine.update(100.0);
         let mut key_msg = String::new();
 
         if event::poll(Duration::from_millis(100)).unwrap() {
             if let event::Event::Key(key) = event::read()? {
-                if key.code == KeyCode::Esc {
+                if let Some(path) = mount.as_mut() {
+                    if key.kind == KeyEventKind::Press {
+                        match key.code {
+                            KeyCode::Enter => {
+                                let path = std::mem::take(path);
+                                mount = None;
+                                let cassette = machine.databus.cassette_mut();
+                                tape_msg = match cassette.load_file(DeckId::Deck2, Path::new(&path), write_back) {
+                                    Ok(()) => format!("Deck 2: {}", path),
+                                    Err(err) => format!("Deck 2: {:#}", err),
+                                };
+                            }
+                            KeyCode::Esc => mount = None,
+                            KeyCode::Backspace => {
+                                path.pop();
+                            }
+                            KeyCode::Char(c) => path.push(c),
+                            _ => {}
+                        }
+                    }
+                } else if key.code == KeyCode::Esc {
                     break;
-                }
-                if key.kind == KeyEventKind::Press {
-                    machine.databus.keyboard.keydown(key.code.to_string());
-                    machine.update(10.0);
-                    machine.databus.keyboard.keyup(key.code.to_string());
-                    key_msg = format!("Key: {} pressed", key.code.to_string());
+                } else if key.code == KeyCode::F(2) {
+                    if key.kind == KeyEventKind::Press {
+                        mount = Some(String::new());
+                    }
+                } else if key.kind == KeyEventKind::Press {
+                    if let Some(dp_key) = keymap.get(&key.code.to_string()) {
+                        if let (Some(recorder), Some(code)) = (recorder.as_mut(), dp_key.code()) {
+                            recorder.key(machine.clock.emulated_time_ns, code)?;
+                        }
+                        machine.databus.keyboard_mut().keydown(dp_key);
+                        machine.update(10.0);
+                        machine.databus.keyboard_mut().keyup(dp_key);
+                    }
+                    key_msg = format!("Key: {} pressed", key.code);
                 }
                 if key.kind == KeyEventKind::Release {
-                    key_msg = format!("Key: {} pressed", key.code.to_string());
+                    key_msg = format!("Key: {} pressed", key.code);
                 }
             }
         }
 
+        let screen = machine.databus.screen_mut();
+        let changes = screen.take_changes();
+        for line in changes.dirty_lines() {
+            lines[line] = screen.get_line(line);
+        }
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.screen(machine.clock.emulated_time_ns, machine.databus.screen(), changes)?;
+        }
+
         terminal.draw(|frame| {
-            let greeting = Paragraph::new(format!("Greetings: {}\n{}", machine.databus.screen.get_screen(), key_msg))
+            let status = match &mount {
+                Some(path) => format!("Tape for deck 2: {}", path),
+                None => tape_msg.clone(),
+            };
+            let greeting = Paragraph::new(format!("Greetings: {}\n{}\n{}", lines.join("\n"), key_msg, status))
                 .white();
             frame.render_widget(greeting, frame.area());
         })?;
     }
 
+    machine.databus.cassette_mut().write_back()?;
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
     Ok(())
 }
\ No newline at end of file